@@ -2,7 +2,7 @@ use alloc::format;
 use uefi::table::{Runtime, SystemTable, cfg::ACPI2_GUID};
 use util::error;
 use util::{
-    acpi::{DescriptionTable, Fadt, Rsdp},
+    acpi::{DescriptionTable, Fadt, Rsdp, apic::Madt},
     error::Result,
     sync::OnceStatic,
 };
@@ -11,10 +11,12 @@ use crate::paging;
 
 pub static FADT: OnceStatic<&'static Fadt> = OnceStatic::new();
 
+pub static MADT: OnceStatic<&'static Madt> = OnceStatic::new();
+
 /// ACPI MMIO base physical address.
 pub static MMIO_PHYS_BASE: OnceStatic<u64> = OnceStatic::new();
 
-/// Set [`FADT`] and [`MMIO_PHYS_BASE`].
+/// Set [`FADT`], [`MADT`] and [`MMIO_PHYS_BASE`].
 pub fn init(runtime: SystemTable<Runtime>) -> Result<()> {
     let rsdp = 'search: {
         for config in runtime.config_table() {
@@ -33,10 +35,12 @@ pub fn init(runtime: SystemTable<Runtime>) -> Result<()> {
     };
 
     let mut fadt = None;
+    let mut madt = None;
     let mut mcfg = None;
     for entry in rsdp.xsdt().unwrap().entries() {
         match entry {
             DescriptionTable::Fadt(entry) => fadt = Some(entry),
+            DescriptionTable::Madt(entry) => madt = Some(entry),
             DescriptionTable::Mcfg(entry) => mcfg = Some(entry),
             _ => {}
         }
@@ -46,6 +50,11 @@ pub fn init(runtime: SystemTable<Runtime>) -> Result<()> {
     }
     FADT.init(fadt.unwrap());
 
+    if madt.is_none() {
+        error!("not found MADT");
+    }
+    MADT.init(madt.unwrap());
+
     if mcfg.is_none() {
         error!("not found MCFG");
     }
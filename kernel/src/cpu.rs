@@ -0,0 +1,390 @@
+//! Provides data owned by each processor.
+//!
+//! GS base of each processor points to its [`PerCpu`]. The kernel runs only in ring 0, so `SWAPGS`
+//! is not required to switch GS base on entries from user mode.
+//!
+//! [`PreemptGuard`] keeps the current task on the processor without disabling interrupts. Timer
+//! ticks defer switching tasks until the last guard is dropped.
+
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    cell::Cell,
+    fmt,
+    marker::PhantomData,
+    mem::offset_of,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering::*},
+};
+
+#[cfg(feature = "lockdep")]
+use util::lockdep::{self, HeldLocks};
+use util::{
+    apic::{self, DeliveryMode},
+    asmfunc,
+    sync::InterruptFreeMutex,
+};
+
+use crate::{
+    interrupt::RESCHED_INT_VEC,
+    paging::TLB_NOT_READY,
+    softirq::SoftirqState,
+    task::{RunQueue, TaskId},
+    timer::TimerState,
+};
+
+/// The maximum number of processors the kernel runs on.
+pub const MAX_CPUS: usize = 64;
+
+/// MSR holding GS base.
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// [`PerCpu`] of each processor indexed by [`PerCpu::id()`], or null if not initialized.
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Data owned by a processor.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Address of itself, read by [`this_cpu()`] at `GS:0`. Must be the first field.
+    this: *const PerCpu,
+    /// Index of the processor. The bootstrap processor is `0`.
+    id: usize,
+    /// Local APIC ID of the processor.
+    apic_id: u8,
+    /// Id of the task running on the processor. Written only by the processor, but read by
+    /// others to find idle processors.
+    current_task: AtomicU64,
+    /// Tasks ready to run on the processor.
+    run_queue: InterruptFreeMutex<RunQueue>,
+    /// Timer state of the processor.
+    timer: TimerState,
+    /// Softirqs raised on the processor.
+    softirq: SoftirqState,
+    /// Depth of sections where preemption is disabled. Incremented at `GS:offset` by
+    /// [`preempt_disable()`] so that the task cannot move to another processor in between.
+    preempt_count: Cell<u32>,
+    /// Whether a timer tick has deferred switching tasks because preemption is disabled.
+    preempt_pending: Cell<bool>,
+    /// The last generation of TLB shootdowns the processor has flushed its TLB for, or
+    /// [`TLB_NOT_READY`] until it can receive them.
+    tlb_generation: AtomicU64,
+    /// The number of sleeping locks held by the running task, or null before tasks start.
+    task_sleeping_locks: Cell<*const AtomicU32>,
+    /// Locks held by the processor with interrupts disabled.
+    #[cfg(feature = "lockdep")]
+    held_locks: HeldLocks,
+    /// Locks held by the running task, which may sleep holding them, or null before tasks start.
+    #[cfg(feature = "lockdep")]
+    task_locks: Cell<*const HeldLocks>,
+}
+
+// Safety: `Cell` fields are accessed only by the owning processor, and written with interrupts
+//         disabled or by single instructions. Others are immutable, atomic or locked.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Returns the index of the processor. The bootstrap processor is `0`.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the Local APIC ID of the processor.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    /// Returns the id of the task running on the processor.
+    pub fn current_task(&self) -> TaskId {
+        TaskId::from_bits(self.current_task.load(Relaxed))
+    }
+
+    /// Sets the id of the task running on the processor. Call this with interrupts disabled.
+    pub(crate) fn set_current_task(&self, id: TaskId) {
+        self.current_task.store(id.to_bits(), Relaxed);
+    }
+
+    /// Returns the run queue of the processor.
+    pub(crate) fn run_queue(&self) -> &InterruptFreeMutex<RunQueue> {
+        &self.run_queue
+    }
+
+    /// Returns the timer state of the processor.
+    pub fn timer(&self) -> &TimerState {
+        &self.timer
+    }
+
+    /// Returns the softirq state of the processor.
+    pub(crate) fn softirq(&self) -> &SoftirqState {
+        &self.softirq
+    }
+
+    /// Returns the depth of sections where preemption is disabled.
+    pub fn preempt_count(&self) -> u32 {
+        self.preempt_count.get()
+    }
+
+    /// Returns the last generation of TLB shootdowns the processor has flushed its TLB for.
+    pub(crate) fn tlb_generation(&self) -> &AtomicU64 {
+        &self.tlb_generation
+    }
+
+    /// Records that switching tasks is deferred until preemption is enabled. Call this on the
+    /// processor with interrupts disabled.
+    pub(crate) fn defer_preemption(&self) {
+        self.preempt_pending.set(true);
+    }
+
+    /// Returns the number of sleeping locks held by the running task, if tasks have started.
+    pub(crate) fn task_sleeping_locks(&self) -> Option<&'static AtomicU32> {
+        // Safety: The running task lives while it runs.
+        unsafe { self.task_sleeping_locks.get().as_ref() }
+    }
+
+    /// Sets the number of sleeping locks held by the task to run. Call this with interrupts
+    /// disabled.
+    pub(crate) fn set_task_sleeping_locks(&self, count: &AtomicU32) {
+        self.task_sleeping_locks.set(count);
+    }
+
+    /// Returns the locks held by the running task, if tasks have started.
+    #[cfg(feature = "lockdep")]
+    pub(crate) fn task_locks(&self) -> Option<&'static HeldLocks> {
+        // Safety: The running task lives while it runs.
+        unsafe { self.task_locks.get().as_ref() }
+    }
+
+    /// Sets the locks held by the task to run. Call this with interrupts disabled.
+    #[cfg(feature = "lockdep")]
+    pub(crate) fn set_task_locks(&self, locks: &HeldLocks) {
+        self.task_locks.set(locks);
+    }
+}
+
+/// Allocates [`PerCpu`] of the current processor with the index `id`, and points GS base to it.
+/// Call this once on each processor after loading segments by [`gdt::init()`](crate::gdt::init),
+/// which clears GS base.
+///
+/// The current task is [`TaskId::INITIAL`] until [`TaskManager::init()`] is called on the
+/// processor.
+///
+/// [`TaskManager::init()`]: crate::task::TaskManager::init
+///
+/// # Panics
+///
+/// Panics if `id` is not less than [`MAX_CPUS`] or already used.
+pub fn init(id: usize, apic_id: u8) {
+    assert!(id < MAX_CPUS, "processor index {} is too large", id);
+    let cpu = Box::leak(Box::new(PerCpu {
+        this: ptr::null(),
+        id,
+        apic_id,
+        current_task: AtomicU64::new(TaskId::INITIAL.to_bits()),
+        run_queue: InterruptFreeMutex::new(RunQueue::new()),
+        timer: TimerState::new(),
+        softirq: SoftirqState::new(),
+        preempt_count: Cell::new(0),
+        preempt_pending: Cell::new(false),
+        tlb_generation: AtomicU64::new(TLB_NOT_READY),
+        task_sleeping_locks: Cell::new(ptr::null()),
+        #[cfg(feature = "lockdep")]
+        held_locks: HeldLocks::new(),
+        #[cfg(feature = "lockdep")]
+        task_locks: Cell::new(ptr::null()),
+    }));
+    cpu.this = cpu;
+    asmfunc::wrmsr(IA32_GS_BASE, cpu as *const PerCpu as u64);
+    let prev = CPUS[id].swap(cpu, Release);
+    assert!(prev.is_null(), "processor index {} is already used", id);
+    #[cfg(feature = "lockdep")]
+    lockdep::set_cpu_locks(|| try_this_cpu().map(|cpu| &cpu.held_locks));
+    #[cfg(feature = "lock-debug")]
+    util::sync::set_cpu_id(|| try_this_cpu().map(|cpu| cpu.apic_id() as u32));
+}
+
+/// Returns [`PerCpu`] of the current processor.
+///
+/// The returned one may not be the current processor's once the task is switched. Disable
+/// interrupts or preemption to keep using it.
+///
+/// # Panics
+///
+/// Faults if [`init()`] has not been called on the current processor.
+pub fn this_cpu() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    // Safety: GS:0 holds the address of `PerCpu` after `init()`.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) cpu,
+            options(nostack, readonly, preserves_flags),
+        );
+        &*cpu
+    }
+}
+
+/// Returns [`PerCpu`] of the current processor, or `None` if [`init()`] has not been called.
+/// This is slower than [`this_cpu()`], so use this only where it may not be initialized, like
+/// fault handlers.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    let cpu = asmfunc::rdmsr(IA32_GS_BASE) as *const PerCpu;
+    // Safety: GS base is either `0` or the address set by `init()`.
+    unsafe { cpu.as_ref() }
+}
+
+/// Returns [`PerCpu`] of the processor whose index is `id`, if initialized.
+pub fn get(id: usize) -> Option<&'static PerCpu> {
+    // Safety: `init()` stores a leaked `PerCpu`.
+    unsafe { CPUS.get(id)?.load(Acquire).as_ref() }
+}
+
+/// Returns an iterator over [`PerCpu`] of all initialized processors in the order of indices.
+pub fn iter() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(get)
+}
+
+/// Disables preemption of the current task until [`preempt_enable()`] is called as many times.
+/// Prefer [`PreemptGuard`], which calls it on drop.
+///
+/// The task must not sleep or yield while preemption is disabled.
+pub fn preempt_disable() {
+    // A single instruction, so the task cannot move to another processor between finding the
+    // counter and incrementing it.
+    // Safety: GS:0 is `PerCpu` after `init()`, and only the processor writes the counter.
+    unsafe {
+        asm!(
+            "inc dword ptr gs:[{}]",
+            const offset_of!(PerCpu, preempt_count),
+            options(nostack),
+        )
+    };
+}
+
+/// Enables preemption disabled by [`preempt_disable()`]. Switches tasks if a timer tick has
+/// deferred it and this is the last call.
+///
+/// # Panics
+///
+/// Panics if preemption is not disabled.
+pub fn preempt_enable() {
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    let cpu = this_cpu();
+    let count = cpu.preempt_count.get();
+    assert_ne!(count, 0, "preemption is not disabled");
+    cpu.preempt_count.set(count - 1);
+    if count == 1 && cpu.preempt_pending.replace(false) {
+        // Switch in the handler of the reschedule IPI, which is delivered once interrupts are
+        // enabled, in the same way as timer ticks.
+        apic::send_ipi(cpu.apic_id(), DeliveryMode::Fixed, RESCHED_INT_VEC);
+    }
+    if if_is_set {
+        asmfunc::sti();
+    }
+}
+
+/// Guard disabling preemption of the current task while it lives. Interrupts are left enabled.
+///
+/// ```ignore
+/// let _guard = PreemptGuard::new();
+/// this_cpu().timer().ticks();
+/// ```
+#[derive(Debug)]
+pub struct PreemptGuard {
+    /// Not `Send` because it has to be dropped on the same processor.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    /// Disables preemption until the guard is dropped.
+    pub fn new() -> Self {
+        preempt_disable();
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Set of processors, used as CPU affinity of tasks.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    /// Constructs a set of no processors.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Constructs a set of all processors.
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    /// Constructs a set of only the processor whose index is `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than [`MAX_CPUS`].
+    pub const fn single(id: usize) -> Self {
+        Self::empty().with(id)
+    }
+
+    /// Returns `self` with the processor whose index is `id` added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than [`MAX_CPUS`].
+    pub const fn with(self, id: usize) -> Self {
+        assert!(id < MAX_CPUS);
+        Self(self.0 | 1 << id)
+    }
+
+    /// Returns `self` with the processor whose index is `id` removed.
+    pub const fn without(self, id: usize) -> Self {
+        if id < MAX_CPUS {
+            Self(self.0 & !(1 << id))
+        } else {
+            self
+        }
+    }
+
+    /// Returns whether the set contains the processor whose index is `id`.
+    pub const fn contains(&self, id: usize) -> bool {
+        id < MAX_CPUS && self.0 & 1 << id != 0
+    }
+
+    /// Returns whether the set contains no processors.
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns an iterator over indices of processors in the set in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + use<> {
+        let bits = self.0;
+        (0..MAX_CPUS).filter(move |&id| bits & 1 << id != 0)
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
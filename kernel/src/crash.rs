@@ -0,0 +1,252 @@
+//! Reports fatal errors with registers and stack backtraces.
+
+use core::{
+    fmt::{self, Display, Write},
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+
+use util::{asmfunc, buffer::StrBuf, graphics::GrayscalePrint as _, screen::Screen};
+
+use crate::{
+    paging,
+    screen::FB_INFO,
+    serial,
+    symbol::{self, Demangle},
+};
+
+/// Maximum number of frames in a backtrace.
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Set when reporting a fatal error to avoid recursive reports.
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// General-purpose and control registers at a fault or a panic.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    /// Captures the registers of the caller. Caller-saved registers hold values when calling
+    /// this, so they are not meaningful.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Self::default();
+        // Safety: only stores registers into `regs`.
+        unsafe {
+            core::arch::asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                "lea {1}, [rip]",
+                "mov [{0} + 0x80], {1}",
+                "pushfq",
+                "pop qword ptr [{0} + 0x88]",
+                "mov word ptr [{0} + 0x90], cs",
+                "mov word ptr [{0} + 0x98], ss",
+                in(reg) &mut regs,
+                out(reg) _,
+            )
+        };
+        regs.cr0 = asmfunc::get_cr0();
+        regs.cr2 = asmfunc::get_cr2();
+        regs.cr3 = asmfunc::get_cr3();
+        regs.cr4 = asmfunc::get_cr4();
+        regs
+    }
+}
+
+impl Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX {:016x} RBX {:016x} RCX {:016x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX {:016x} RSI {:016x} RDI {:016x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP {:016x} RSP {:016x} R8  {:016x}",
+            self.rbp, self.rsp, self.r8
+        )?;
+        writeln!(
+            f,
+            "R9  {:016x} R10 {:016x} R11 {:016x}",
+            self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12 {:016x} R13 {:016x} R14 {:016x}",
+            self.r12, self.r13, self.r14
+        )?;
+        writeln!(
+            f,
+            "R15 {:016x} RIP {:016x} RFL {:016x}",
+            self.r15, self.rip, self.rflags
+        )?;
+        writeln!(f, "CS  {:04x} SS  {:04x}", self.cs, self.ss)?;
+        writeln!(
+            f,
+            "CR0 {:016x} CR2 {:016x} CR3 {:016x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        writeln!(f, "CR4 {:016x}", self.cr4)
+    }
+}
+
+/// Writes output both to the serial port and to the buffer displayed on the screen by
+/// [`CrashWriter::show()`].
+pub struct CrashWriter<'buf> {
+    buf: StrBuf<'buf>,
+}
+
+impl<'buf> CrashWriter<'buf> {
+    /// Constructs [`CrashWriter`] with `buf` used for the screen.
+    pub fn new(buf: &'buf mut [u8]) -> Self {
+        Self {
+            buf: StrBuf::new(buf),
+        }
+    }
+
+    /// Displays written text on the screen if [`FB_INFO`] is initialized.
+    pub fn show(&self) {
+        if FB_INFO.is_initialized() {
+            let mut screen = Screen::new(FB_INFO.as_ref().clone());
+            screen.clear();
+            screen.print_str(self.buf.to_str(), (0, 0));
+        }
+    }
+}
+
+impl Write for CrashWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write_bytes(s.as_bytes());
+        // Text over the buffer is only on the serial port.
+        let _ = self.buf.write_str(s);
+        Ok(())
+    }
+}
+
+/// Writes `regs` and the backtrace from them to `w`.
+pub fn dump(w: &mut dyn Write, regs: &Registers) -> fmt::Result {
+    write!(w, "{}", regs)?;
+    write_backtrace(w, regs.rip, regs.rbp)
+}
+
+/// Writes the backtrace of the frame whose instruction pointer is `rip` and frame pointer is
+/// `rbp` by walking the chain of saved frame pointers.
+pub fn write_backtrace(w: &mut dyn Write, rip: u64, mut rbp: u64) -> fmt::Result {
+    writeln!(w, "backtrace:")?;
+    write_frame(w, 0, rip, rip)?;
+    for depth in 1..MAX_BACKTRACE_DEPTH {
+        if !is_valid_frame(rbp) {
+            break;
+        }
+        // Safety: `rbp` and `rbp + 8` are mapped.
+        let (next_rbp, ret_addr) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if ret_addr == 0 {
+            break;
+        }
+        // The call instruction is just before the return address, which may be in the next
+        // function if the call is at the end of the function.
+        write_frame(w, depth, ret_addr, ret_addr - 1)?;
+
+        // Callers' frames are always above because stacks grow downward.
+        if next_rbp <= rbp {
+            break;
+        }
+        rbp = next_rbp;
+    }
+    Ok(())
+}
+
+/// Writes a line of a backtrace for `addr` with the symbol containing `lookup_addr`.
+fn write_frame(w: &mut dyn Write, depth: usize, addr: u64, lookup_addr: u64) -> fmt::Result {
+    match symbol::symbolize(lookup_addr) {
+        Some((name, offset)) => writeln!(
+            w,
+            "  #{:<2} {:016x} {}+{:#x}",
+            depth,
+            addr,
+            Demangle(name),
+            offset + (addr - lookup_addr)
+        ),
+        None => writeln!(w, "  #{:<2} {:016x}", depth, addr),
+    }
+}
+
+/// Returns whether `rbp` looks like a frame pointer of the kernel.
+fn is_valid_frame(rbp: u64) -> bool {
+    // Safety: page tables are modified by nobody while crashing, or the result is just wrong.
+    paging::KERNEL_PML4.is_initialized()
+        && rbp >= paging::STRAIGHT_PAGE_MAP_BASE.addr
+        && rbp.is_multiple_of(8)
+        && unsafe { paging::translate_unlocked(rbp) }.is_some()
+        && unsafe { paging::translate_unlocked(rbp + 8) }.is_some()
+}
+
+/// Reports a fatal error written by `report` to the serial port and the screen, and halts the
+/// processor.
+pub fn die(report: impl FnOnce(&mut CrashWriter) -> fmt::Result) -> ! {
+    asmfunc::cli();
+    if CRASHING.swap(true, Relaxed) {
+        serial::write_bytes(b"fatal error while reporting another one\n");
+        halt();
+    }
+
+    let mut buf = [0; 4 * 1024];
+    let mut w = CrashWriter::new(&mut buf);
+    let _ = report(&mut w);
+    w.show();
+    halt();
+}
+
+fn halt() -> ! {
+    loop {
+        asmfunc::hlt();
+    }
+}
@@ -5,13 +5,21 @@ use util::{
     driver::AhciConfig,
     error::Result,
     pci::{Capability, ConfigSpaces},
-    sync::OnceStatic,
+    sync::Lazy,
 };
 
 use crate::acpi::MMIO_PHYS_BASE;
 use crate::printkln;
 
-pub static CONFIG_SPACES: OnceStatic<ConfigSpaces> = OnceStatic::new();
+/// PCI configuration spaces, mapped on first use.
+///
+/// # Panics
+///
+/// Panics on first use if [`MMIO_PHYS_BASE`] is not initialized.
+pub static CONFIG_SPACES: Lazy<ConfigSpaces> = Lazy::new(|| {
+    // Safety: MMIO_PHSY_BASE is passed by UEFI, so it must meet the condition.
+    unsafe { ConfigSpaces::from_ptr(*MMIO_PHYS_BASE as _) }
+});
 
 /// Initializes drivers.
 ///
@@ -19,9 +27,6 @@ pub static CONFIG_SPACES: OnceStatic<ConfigSpaces> = OnceStatic::new();
 ///
 /// It will cause panic if called before [MMIO_PHYS_BASE] is initialized.
 pub fn init() -> Result<()> {
-    // Safety: MMIO_PHSY_BASE is passed by UEFI, so it must meet the condition.
-    CONFIG_SPACES.init(unsafe { ConfigSpaces::from_ptr(*MMIO_PHYS_BASE as _) });
-
     let Some(ahci_bfd) = CONFIG_SPACES
         .valid_bfds_and_classes()
         .find(|(class, _)| {
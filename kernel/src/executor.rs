@@ -0,0 +1,255 @@
+//! Runs futures in kernel tasks.
+//!
+//! [`block_on()`] polls a future in the current task, and [`Executor`] polls many futures in a
+//! worker task, so outstanding requests do not need a task each. Wakers wake up the polling task
+//! with [`TaskManager::wake_up()`](crate::task::TaskManager::wake_up), which can be called from
+//! interrupt handlers.
+//!
+//! ```ignore
+//! executor::spawn(async move {
+//!     let status = port.issue(command).await;
+//!     executor::sleep(Duration::from_millis(10)).await;
+//! });
+//! ```
+
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, sync::Arc, task::Wake};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::{Pin, pin},
+    sync::atomic::{AtomicBool, Ordering::*},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use util::{
+    error::Result,
+    sync::{InterruptFreeMutex, OnceStatic},
+};
+
+use crate::{
+    sync::{WaitQueue, wait_until},
+    task::{Builder, TASK_MANAGER, TaskId, TimerId},
+    timer::Instant,
+};
+
+/// Executor shared by the whole kernel.
+pub static EXECUTOR: OnceStatic<Arc<Executor>> = OnceStatic::new();
+
+/// Creates [`EXECUTOR`]. Call this after [`TaskManager::init()`](crate::task::TaskManager::init).
+pub fn init() -> Result<()> {
+    EXECUTOR.init(Executor::new("async")?);
+    Ok(())
+}
+
+/// Runs `future` on [`EXECUTOR`]. See [`Executor::spawn()`].
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    EXECUTOR.spawn(future);
+}
+
+/// Runs `future` to completion in the current task, sleeping while it is pending.
+///
+/// Do not call this from idle tasks, which must never sleep.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    debug_assert!(!TASK_MANAGER.is_idle());
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(TaskWaker(TASK_MANAGER.task_id())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // A wake-up while polling makes `sleep()` return immediately, so it is not lost.
+        TASK_MANAGER.sleep();
+    }
+}
+
+/// Waker of a task polling a future in [`block_on()`].
+struct TaskWaker(TaskId);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        TASK_MANAGER.wake_up(self.0);
+    }
+}
+
+/// Polls futures in a worker task. A future is polled again only after its waker is woken.
+pub struct Executor {
+    name: String,
+    state: InterruptFreeMutex<State>,
+}
+
+/// State of [`Executor`] shared with wakers.
+struct State {
+    /// Futures woken and not polled yet.
+    ready: VecDeque<Arc<Job>>,
+    /// The worker task sleeping until a future is woken.
+    worker: WaitQueue,
+}
+
+impl Executor {
+    /// Creates an executor named `name` with its worker task.
+    pub fn new(name: &str) -> Result<Arc<Self>> {
+        let executor = Arc::new(Self {
+            name: name.into(),
+            state: InterruptFreeMutex::new(State {
+                ready: VecDeque::new(),
+                worker: WaitQueue::new(),
+            }),
+        });
+        let this = Arc::clone(&executor);
+        Builder::new()
+            .name(format!("executor/{}", name))
+            .spawn(move || this.run())?;
+        Ok(executor)
+    }
+
+    /// Returns the name of the executor.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs `future` on the executor. Can be called from interrupt handlers.
+    pub fn spawn(self: &Arc<Self>, future: impl Future<Output = ()> + Send + 'static) {
+        let job = Arc::new(Job {
+            future: UnsafeCell::new(Some(Box::pin(future))),
+            queued: AtomicBool::new(false),
+            executor: Arc::clone(self),
+        });
+        job.schedule();
+    }
+
+    /// Main loop of the worker task.
+    fn run(&self) {
+        loop {
+            let job = wait_until(
+                &self.state,
+                |state| &mut state.worker,
+                |state| state.ready.pop_front(),
+            );
+
+            // Clear it first so that a wake-up while polling queues the job again.
+            job.queued.store(false, Release);
+            let waker = Waker::from(Arc::clone(&job));
+            let mut cx = Context::from_waker(&waker);
+            // Safety: Only the worker task accesses the future.
+            let future = unsafe { &mut *job.future.get() };
+            if let Some(pending) = future
+                && pending.as_mut().poll(&mut cx).is_ready()
+            {
+                *future = None;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("name", &self.name)
+            .field("ready", &self.state.lock().ready.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Future spawned on an [`Executor`], which is also its waker.
+struct Job {
+    /// `None` after the future completes.
+    future: UnsafeCell<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// Whether the job is in the ready queue of the executor.
+    queued: AtomicBool,
+    executor: Arc<Executor>,
+}
+
+// Safety: `future` is accessed only by the worker task of the executor. Others are `Sync`.
+unsafe impl Sync for Job {}
+
+impl Job {
+    /// Queues the job to be polled, unless it is already queued.
+    fn schedule(self: &Arc<Self>) {
+        if self.queued.swap(true, AcqRel) {
+            return;
+        }
+        let mut state = self.executor.state.lock();
+        state.ready.push_back(Arc::clone(self));
+        state.worker.wake_one();
+    }
+}
+
+impl Wake for Job {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+/// Returns a future that completes after `duration`, as precisely as
+/// [`TaskManager::sleep_until()`](crate::task::TaskManager::sleep_until) wakes up.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Returns a future that completes at `deadline`, as precisely as
+/// [`TaskManager::sleep_until()`](crate::task::TaskManager::sleep_until) wakes up.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Future returned by [`sleep()`] and [`sleep_until()`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    deadline: Instant,
+    /// Timer registered with the waker it wakes.
+    timer: Option<(TimerId, Waker)>,
+}
+
+impl Sleep {
+    /// Returns when the future completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // Register again only if the future has moved to another task.
+        if !self
+            .timer
+            .as_ref()
+            .is_some_and(|(_, waker)| waker.will_wake(cx.waker()))
+        {
+            if let Some((id, _)) = self.timer.take() {
+                TASK_MANAGER.cancel_timer(id);
+            }
+            let id = TASK_MANAGER.wake_at(self.deadline, cx.waker().clone());
+            self.timer = Some((id, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // Otherwise the timer keeps the waker until the deadline.
+        if let Some((id, _)) = self.timer.take() {
+            TASK_MANAGER.cancel_timer(id);
+        }
+    }
+}
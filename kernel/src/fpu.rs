@@ -0,0 +1,151 @@
+//! Saves and restores FPU, SSE and AVX registers of tasks.
+//!
+//! Registers are switched eagerly on every task switch with XSAVE, or FXSAVE if the processor
+//! does not support it. The kernel is built without SSE, so interrupt handlers leave the registers
+//! of the interrupted task intact until it is switched.
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error};
+use core::{
+    arch::asm,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering::*},
+};
+
+use util::{asmfunc, bitfield::BitField as _, sync::OnceStatic};
+
+/// Size of the area saved by FXSAVE.
+const FXSAVE_AREA_SIZE: usize = 512;
+
+/// Alignment required by XSAVE. FXSAVE requires 16.
+const AREA_ALIGN: usize = 64;
+
+/// State components enabled in XCR0: x87, SSE and AVX.
+const XCR0_FEATURES: u64 = 0b111;
+
+/// Initial value of the x87 control word, which masks all exceptions.
+const FCW_INIT: u16 = 0x37f;
+
+/// Initial value of MXCSR, which masks all exceptions.
+const MXCSR_INIT: u32 = 0x1f80;
+
+/// Whether registers are saved with XSAVE instead of FXSAVE. Read by `_switch_context` and
+/// `_restore_context`.
+pub static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// XCR0 value enabled on all processors.
+static XCR0: OnceStatic<u64> = OnceStatic::new();
+
+/// Size of [`FpuArea`].
+static AREA_SIZE: OnceStatic<usize> = OnceStatic::new();
+
+/// Enables FPU, SSE and AVX on the current processor. Call this on each processor before
+/// [`TaskManager::init()`](crate::task::TaskManager::init), first on the bootstrap processor.
+///
+/// Application processors enable the same state components as the bootstrap processor so that
+/// every [`FpuArea`] fits all of them.
+pub fn init() {
+    let (_, _, ecx, _) = asmfunc::cpuid(1);
+    let xsave = ecx.get_bit(26);
+
+    let mut cr0 = asmfunc::get_cr0();
+    cr0.set_bit(1, true); // MP: Let WAIT instructions check TS.
+    cr0.set_bit(2, false); // EM: Execute x87 instructions instead of raising #NM.
+    cr0.set_bit(3, false); // TS: Registers are switched eagerly, so never raise #NM.
+    cr0.set_bit(5, true); // NE: Report x87 errors by #MF.
+    asmfunc::set_cr0(cr0);
+
+    let mut cr4 = asmfunc::get_cr4();
+    cr4.set_bit(9, true); // OSFXSR: Enable SSE and FXSAVE.
+    cr4.set_bit(10, true); // OSXMMEXCPT: Report SSE errors by #XM.
+    cr4.set_bit(18, xsave); // OSXSAVE: Enable XSAVE and XCR0.
+    asmfunc::set_cr4(cr4);
+
+    if xsave {
+        let (eax, _, _, edx) = asmfunc::cpuid_count(0xd, 0);
+        let supported = eax as u64 | (edx as u64) << 32;
+        XCR0.init(supported & XCR0_FEATURES);
+        asmfunc::xsetbv(0, XCR0.get());
+        // EBX is the size required by the components enabled in XCR0.
+        let (_, size, _, _) = asmfunc::cpuid_count(0xd, 0);
+        AREA_SIZE.init(size as usize);
+    } else {
+        AREA_SIZE.init(FXSAVE_AREA_SIZE);
+    }
+    USE_XSAVE.store(xsave, Relaxed);
+
+    unsafe { asm!("fninit") };
+}
+
+/// Memory to save FPU, SSE and AVX registers of a task.
+#[derive(Debug)]
+pub struct FpuArea {
+    ptr: NonNull<u8>,
+}
+
+// Safety: `FpuArea` owns the memory like `Box`.
+unsafe impl Send for FpuArea {}
+unsafe impl Sync for FpuArea {}
+
+impl FpuArea {
+    /// Allocates an area holding the initial state, where all exceptions are masked. Call
+    /// [`init()`] first.
+    pub fn new() -> Self {
+        let layout = Self::layout();
+        // Safety: `layout` is not zero-sized.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            handle_alloc_error(layout);
+        };
+        // The legacy region has the same layout for XSAVE and FXSAVE. The XSAVE header is zero,
+        // so XRSTOR initializes other components.
+        // Safety: The area is larger than the legacy region, and aligned.
+        unsafe {
+            ptr.cast::<u16>().write(FCW_INIT);
+            ptr.byte_add(24).cast::<u32>().write(MXCSR_INIT);
+        }
+        Self { ptr }
+    }
+
+    /// Returns the address of the area.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(AREA_SIZE.get(), AREA_ALIGN).unwrap()
+    }
+}
+
+impl Default for FpuArea {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuArea {
+    fn drop(&mut self) {
+        // Safety: `self.ptr` is allocated with the same layout in `new()`.
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout()) };
+    }
+}
+
+/// Saves the registers of the current processor to the area at `area`.
+///
+/// # Safety
+///
+/// `area` must be the address of an [`FpuArea`].
+pub unsafe fn save(area: *mut u8) {
+    if USE_XSAVE.load(Relaxed) {
+        unsafe {
+            asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            )
+        };
+    } else {
+        unsafe { asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)) };
+    }
+}
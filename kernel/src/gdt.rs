@@ -0,0 +1,68 @@
+//! Sets up the GDT, the TSS and stacks used by interrupts.
+
+use alloc::boxed::Box;
+
+use util::{
+    asmfunc,
+    descriptor::{GDT, SegmentDescriptor, SegmentType, SystemDescriptor, TSS},
+    error::Result,
+};
+
+use crate::paging;
+
+/// Segment selector of the kernel code segment.
+pub const KERNEL_CS: u16 = 1 << 3;
+
+/// Segment selector of the kernel data segment.
+pub const KERNEL_SS: u16 = 2 << 3;
+
+/// Segment selector of the TSS.
+const TSS_SELECTOR: u16 = 3 << 3;
+
+/// IST index of the stack used by timer interrupts.
+pub const IST_TIMER: u8 = 1;
+
+/// IST index of the stack used by double faults.
+pub const IST_DOUBLE_FAULT: u8 = 2;
+
+/// IST index of the stack used by non-maskable interrupts.
+pub const IST_NMI: u8 = 3;
+
+/// IST index of the stack used by machine checks.
+pub const IST_MACHINE_CHECK: u8 = 4;
+
+/// Size of each IST stack in pages. Fault handlers need enough space to format reports.
+const IST_STACK_PAGES: usize = 4;
+
+/// Sets up a GDT and a TSS for the current processor and loads them.
+///
+/// Each IST stack has an unmapped guard page below it, so exceptions are handled on a valid
+/// stack even if the interrupted one is broken.
+pub fn init() -> Result<()> {
+    // In the order of IST indices.
+    let names = [
+        "timer IST stack",
+        "#DF IST stack",
+        "NMI IST stack",
+        "#MC IST stack",
+    ];
+    let mut ists = [0; 4];
+    for (ist, name) in ists.iter_mut().zip(names) {
+        *ist = paging::reserve_stack(IST_STACK_PAGES, name, None)?.end;
+    }
+    // The TSS and the GDT are referenced by the processor as long as it runs.
+    let tss = Box::leak(Box::new(TSS::new(&[], &ists)));
+
+    let mut gdt = GDT::new(5);
+    gdt.set(1, SegmentDescriptor::new(SegmentType::code(true, false), 0))?;
+    gdt.set(2, SegmentDescriptor::new(SegmentType::data(true, false), 0))?;
+    gdt.set(3, SystemDescriptor::new_tss(tss, 0))?;
+    let gdt = Box::leak(Box::new(gdt));
+
+    gdt.register();
+    asmfunc::set_cs_ss(KERNEL_CS, KERNEL_SS);
+    asmfunc::set_ds_all(0);
+    asmfunc::load_tr(TSS_SELECTOR);
+
+    Ok(())
+}
@@ -1,73 +1,92 @@
 //! Configure interrupts settings.
 
-use core::arch::global_asm;
+use alloc::{boxed::Box, format, vec::Vec};
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    mem,
+};
 
 use util::{
+    apic, asmfunc,
+    bitfield::BitField as _,
     descriptor::{self, SystemDescriptor},
+    error,
     error::Result,
-    sync::OnceStatic,
+    interrupt::{InterruptFrame, PageFaultErrorCode},
+    sync::{InterruptFreeMutex, OnceStatic},
+};
+
+use crate::{
+    cpu::try_this_cpu,
+    crash::{self, Registers},
+    gdt,
+    paging::{self, PageFault, Region, RegionKind},
+    serial::SerialWriter,
+    task::{TASK_MANAGER, TaskId},
 };
 
 pub const TIMER_INT_VEC: u8 = 0x40;
 
-/// Declares default interrupt handler function named `int_handler_<arg>` without an error code.
-/// Declared function prints capital `arg`, RIP, CS, RFLAGS, RSP and SS on the screen if
-/// [`FB_INFO`](kernel::screen::FB_IFNO) is already set.
-macro_rules! fault_handler_no_error {
-    ($name:ident) => {
-        paste::paste! {
-            #[util::interrupt_handler]
-            fn [<int_handler_ $name:lower>](frame: &util::interrupt::InterruptFrame) {
-                use core::fmt::Write as _;
-                use util::graphics::GrayscalePrint as _;
-
-                let mut buf = [0; 256];
-                let mut buf = util::buffer::StrBuf::new(&mut buf);
-                let _ = writeln!(buf, concat!("#", core::stringify!([<$name:upper>])));
-                let _ = writeln!(buf, "CS:RIP {:04x} {:016x}", frame.cs, frame.rip);
-                let _ = writeln!(buf, "RFLAGS {:016x}", frame.rflags);
-                let _ = writeln!(buf, "SS:RSP {:04x} {:016x}", frame.ss, frame.rsp);
-                let mut screen =
-                    util::screen::Screen::new(crate::screen::FB_INFO.as_ref().clone());
-                screen.clear();
-                screen.print_str(buf.to_str(), (500, 0));
-                loop {
-                    util::asmfunc::hlt();
-                }
-            }
-        }
-    };
-}
+/// Vector of inter-processor interrupts asking the receiver to reschedule tasks.
+pub const RESCHED_INT_VEC: u8 = DYNAMIC_VEC_END;
 
-/// Declares default interrupt handler function named `int_handler_<arg>` with an error code.
-/// Declared function prints capital `arg`, RIP, CS, RFLAGS, RSP, SS and error code on the screen if
-/// [`FB_INFO`](kernel::screen::FB_IFNO) is already set.
-macro_rules! fault_handler_with_error {
-    ($name:ident) => {
-        paste::paste! {
-            #[util::interrupt_handler]
-            fn [<int_handler_ $name:lower>](frame: &util::interrupt::InterruptFrame, error_code: u64) {
-                use core::fmt::Write as _;
-                use util::graphics::GrayscalePrint as _;
-
-                let mut buf = [0; 256];
-                let mut buf = util::buffer::StrBuf::new(&mut buf);
-                let _ = writeln!(buf, concat!("#", core::stringify!([<$name:upper>])));
-                let _ = writeln!(buf, "CS:RIP {:04x} {:016x}", frame.cs, frame.rip);
-                let _ = writeln!(buf, "RFLAGS {:016x}", frame.rflags);
-                let _ = writeln!(buf, "SS:RSP {:04x} {:016x}", frame.ss, frame.rsp);
-                let _ = writeln!(buf, "ERR    {:016x}", error_code);
-                let mut screen =
-                    util::screen::Screen::new(crate::screen::FB_INFO.as_ref().clone());
-                screen.clear();
-                screen.print_str(buf.to_str(), (500, 0));
-                loop {
-                    util::asmfunc::hlt();
-                }
-            }
-        }
-    };
-}
+/// Vector of inter-processor interrupts a processor sends to itself to run softirqs raised by
+/// [`softirq::raise()`](crate::softirq::raise).
+pub const SOFTIRQ_INT_VEC: u8 = DYNAMIC_VEC_END + 1;
+
+/// Vector of inter-processor interrupts asking the receiver to flush its TLB, sent by
+/// [`paging::flush_tlb_all()`].
+pub const TLB_FLUSH_INT_VEC: u8 = DYNAMIC_VEC_END + 2;
+
+/// Vector of spurious interrupts from Local APIC.
+pub const SPURIOUS_INT_VEC: u8 = 0xff;
+
+/// The first vector that [`allocate_vector()`] can return.
+pub const DYNAMIC_VEC_START: u8 = 0x50;
+
+/// The end (exclusive) of vectors that [`allocate_vector()`] can return. Vectors from this are
+/// reserved for inter-processor interrupts.
+pub const DYNAMIC_VEC_END: u8 = 0xf0;
+
+/// The number of vectors that can be dynamically allocated.
+const DYNAMIC_VEC_COUNT: usize = (DYNAMIC_VEC_END - DYNAMIC_VEC_START) as usize;
+
+/// Size of each stub in [`int_dispatch_stubs`] in bytes.
+const DISPATCH_STUB_SIZE: usize = 8;
+
+/// Size of each stub in [`fault_stubs`] in bytes.
+const FAULT_STUB_SIZE: usize = 16;
+
+/// Exception vectors handled by [`_fault_dispatch()`], their names and IST indices of the stacks
+/// used by them (`0` for the current stack).
+const FAULTS: [(u8, &str, u8); 19] = [
+    (0, "#DE Divide Error", 0),
+    (1, "#DB Debug", 0),
+    (2, "NMI Non-Maskable Interrupt", gdt::IST_NMI),
+    (3, "#BP Breakpoint", 0),
+    (4, "#OF Overflow", 0),
+    (5, "#BR BOUND Range Exceeded", 0),
+    (6, "#UD Invalid Opcode", 0),
+    (7, "#NM Device Not Available", 0),
+    (8, "#DF Double Fault", gdt::IST_DOUBLE_FAULT),
+    (10, "#TS Invalid TSS", 0),
+    (11, "#NP Segment Not Present", 0),
+    (12, "#SS Stack-Segment Fault", 0),
+    (13, "#GP General Protection", 0),
+    (14, "#PF Page Fault", 0),
+    (16, "#MF x87 Floating-Point Error", 0),
+    (17, "#AC Alignment Check", 0),
+    (18, "#MC Machine Check", gdt::IST_MACHINE_CHECK),
+    (19, "#XM SIMD Floating-Point Exception", 0),
+    (20, "#VE Virtualization Exception", 0),
+];
+
+/// Vector of double faults.
+const DOUBLE_FAULT_VEC: u8 = 8;
+
+/// Vector of page faults.
+const PAGE_FAULT_VEC: u8 = 14;
 
 /// Interrupt Descriptor table for kernel initialized at the beggining.
 static IDT: OnceStatic<descriptor::IDT> = OnceStatic::new();
@@ -75,82 +94,47 @@ static IDT: OnceStatic<descriptor::IDT> = OnceStatic::new();
 /// Initialize IDT for kernel and load it to a processor..
 pub fn init() -> Result<()> {
     let mut idt = descriptor::IDT::new();
+    for (vector, _, ist) in FAULTS {
+        let stub = fault_stubs as *const () as usize + vector as usize * FAULT_STUB_SIZE;
+        // Safety: `stub` points to the stub of `vector` in `fault_stubs`, which is an interrupt
+        //     handler entry.
+        let stub = unsafe { mem::transmute::<usize, unsafe extern "sysv64" fn()>(stub) };
+        idt.set(
+            vector as _,
+            SystemDescriptor::new_interrupt(stub, gdt::KERNEL_CS, ist, 0),
+        )?;
+    }
     idt.set(
-        0,
-        SystemDescriptor::new_interrupt(int_handler_de, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        1,
-        SystemDescriptor::new_interrupt(int_handler_db, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        3,
-        SystemDescriptor::new_interrupt(int_handler_bp, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        4,
-        SystemDescriptor::new_interrupt(int_handler_of, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        5,
-        SystemDescriptor::new_interrupt(int_handler_br, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        6,
-        SystemDescriptor::new_interrupt(int_handler_ud, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        7,
-        SystemDescriptor::new_interrupt(int_handler_nm, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        8,
-        SystemDescriptor::new_interrupt(int_handler_df, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        10,
-        SystemDescriptor::new_interrupt(int_handler_ts, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        11,
-        SystemDescriptor::new_interrupt(int_handler_np, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        12,
-        SystemDescriptor::new_interrupt(int_handler_ss, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        13,
-        SystemDescriptor::new_interrupt(int_handler_gp, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        14,
-        SystemDescriptor::new_interrupt(int_handler_pf, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        16,
-        SystemDescriptor::new_interrupt(int_handler_mf, 1 << 3, 0, 0),
-    )?;
-    idt.set(
-        17,
-        SystemDescriptor::new_interrupt(int_handler_ac, 1 << 3, 0, 0),
+        TIMER_INT_VEC as _,
+        SystemDescriptor::new_interrupt(int_handler_timer, gdt::KERNEL_CS, gdt::IST_TIMER, 0),
     )?;
     idt.set(
-        18,
-        SystemDescriptor::new_interrupt(int_handler_mc, 1 << 3, 0, 0),
+        RESCHED_INT_VEC as _,
+        SystemDescriptor::new_interrupt(int_handler_resched, gdt::KERNEL_CS, gdt::IST_TIMER, 0),
     )?;
+    // Softirqs run with interrupts enabled, so the handler must not use an IST stack.
     idt.set(
-        19,
-        SystemDescriptor::new_interrupt(int_handler_xm, 1 << 3, 0, 0),
+        SOFTIRQ_INT_VEC as _,
+        SystemDescriptor::new_interrupt(int_handler_softirq, gdt::KERNEL_CS, 0, 0),
     )?;
     idt.set(
-        20,
-        SystemDescriptor::new_interrupt(int_handler_ve, 1 << 3, 0, 0),
+        TLB_FLUSH_INT_VEC as _,
+        SystemDescriptor::new_interrupt(int_handler_tlb_flush, gdt::KERNEL_CS, 0, 0),
     )?;
     idt.set(
-        TIMER_INT_VEC as _,
-        SystemDescriptor::new_interrupt(int_handler_timer, 1 << 3, 1, 0),
+        SPURIOUS_INT_VEC as _,
+        SystemDescriptor::new_interrupt(int_handler_spurious, gdt::KERNEL_CS, 0, 0),
     )?;
+    for i in 0..DYNAMIC_VEC_COUNT {
+        let stub = int_dispatch_stubs as *const () as usize + i * DISPATCH_STUB_SIZE;
+        // Safety: `stub` points to the `i`-th stub in `int_dispatch_stubs`, which is an interrupt
+        //     handler entry.
+        let stub = unsafe { mem::transmute::<usize, unsafe extern "sysv64" fn()>(stub) };
+        idt.set(
+            DYNAMIC_VEC_START as usize + i,
+            SystemDescriptor::new_interrupt(stub, gdt::KERNEL_CS, 0, 0),
+        )?;
+    }
 
     IDT.init(idt);
     IDT.as_ref().register();
@@ -158,34 +142,543 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 
-fault_handler_no_error!(DE);
-fault_handler_no_error!(DB);
-fault_handler_no_error!(BP);
-fault_handler_no_error!(OF);
-fault_handler_no_error!(BR);
-fault_handler_no_error!(UD);
-fault_handler_no_error!(NM);
-fault_handler_with_error!(DF);
-fault_handler_with_error!(TS);
-fault_handler_with_error!(NP);
-fault_handler_with_error!(SS);
-fault_handler_with_error!(GP);
-fault_handler_with_error!(PF);
-fault_handler_no_error!(MF);
-fault_handler_with_error!(AC);
-fault_handler_no_error!(MC);
-fault_handler_no_error!(XM);
-fault_handler_no_error!(VE);
+/// Loads the IDT initialized by [`init()`] to an application processor.
+pub fn init_ap() {
+    IDT.as_ref().register();
+}
+
+/// Registers saved by `fault_common` on exceptions.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct FaultContext {
+    pub cr2: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Exception vector.
+    pub vector: u64,
+    /// Error code pushed by a processor, or `0` if the exception has no error code.
+    pub error_code: u64,
+    pub frame: InterruptFrame,
+}
+
+impl FaultContext {
+    /// Returns registers at the exception. Control registers other than CR2 are read now.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            rsp: self.frame.rsp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.frame.rip,
+            rflags: self.frame.rflags,
+            cs: self.frame.cs,
+            ss: self.frame.ss,
+            cr0: asmfunc::get_cr0(),
+            cr2: self.cr2,
+            cr3: asmfunc::get_cr3(),
+            cr4: asmfunc::get_cr4(),
+        }
+    }
+
+    /// Writes the header line of the exception, all registers and the backtrace to `w`.
+    fn dump(&self, w: &mut dyn Write) -> fmt::Result {
+        let name = FAULTS
+            .iter()
+            .find(|(vector, _, _)| *vector as u64 == self.vector)
+            .map_or("unknown exception", |(_, name, _)| name);
+        writeln!(
+            w,
+            "{} (vector {}, error code {:#x}) in task {}",
+            name,
+            self.vector,
+            self.error_code,
+            try_this_cpu().map_or(TaskId::INITIAL, |cpu| cpu.current_task())
+        )?;
+        crash::dump(w, &self.registers())
+    }
+}
+
+/// Handles exceptions. Called by `fault_common` with the registers saved on the stack. If this
+/// returns, the interrupted code resumes with the registers in `ctx`.
+#[unsafe(no_mangle)]
+extern "sysv64" fn _fault_dispatch(ctx: &mut FaultContext) {
+    if ctx.vector == PAGE_FAULT_VEC as u64 {
+        page_fault(ctx);
+    } else if ctx.vector == DOUBLE_FAULT_VEC as u64 {
+        double_fault(ctx);
+    } else {
+        crash::die(|w| ctx.dump(w));
+    }
+}
+
+/// Handles page faults. Faults in [`RegionKind::Lazy`](paging::RegionKind::Lazy) regions are
+/// resolved by mapping pages. Otherwise, reports the fault to the serial port and terminates the
+/// current task, or halts if it is not safe.
+fn page_fault(ctx: &FaultContext) {
+    let error = PageFaultErrorCode::new(ctx.error_code);
+    let fault = paging::handle_page_fault(ctx.cr2, error);
+    if fault == PageFault::Resolved {
+        return;
+    }
+
+    let report = |w: &mut dyn Write| -> fmt::Result {
+        writeln!(w, "{:016x}: {}", ctx.cr2, error)?;
+        match fault {
+            PageFault::Guard(Region {
+                owner: Some(owner), ..
+            }) => writeln!(w, "stack overflow in task {}", owner),
+            PageFault::Guard(region) => writeln!(
+                w,
+                "guard page of {} ({:016x}-{:016x}) accessed",
+                region.name, region.start, region.end
+            ),
+            PageFault::Invalid(Some(region)) => writeln!(
+                w,
+                "invalid access to {} ({:016x}-{:016x}, {:?})",
+                region.name, region.start, region.end, region.kind
+            ),
+            _ => writeln!(w, "no region contains the address"),
+        }?;
+        ctx.dump(w)
+    };
+
+    // Terminating the task is safe only when it does not hold any `InterruptFreeMutex`, which
+    // disables interrupts while locked, nor `PreemptGuard`, whose count would be left on the
+    // processor, nor sleeping locks, whose waiters would sleep forever.
+    if let Some(cpu) = try_this_cpu()
+        && ctx.frame.rflags.get_bit(9)
+        && cpu.preempt_count() == 0
+        && !TASK_MANAGER.is_idle()
+        && !TASK_MANAGER.holds_sleeping_locks()
+    {
+        let task_id = cpu.current_task();
+        let mut serial = SerialWriter;
+        let _ = report(&mut serial);
+        let _ = writeln!(serial, "task {} killed", task_id);
+        TASK_MANAGER.exit();
+    }
+
+    crash::die(|w| report(w));
+}
+
+/// Reports a double fault and halts. Page faults are handled on the current stack, so overflowing
+/// a stack into its guard page raises a double fault, which is handled on its own IST stack.
+fn double_fault(ctx: &FaultContext) -> ! {
+    crash::die(|w| {
+        // CR2 holds the address of the page fault which failed to be delivered, if any.
+        if let Some(region) = paging::try_find_region(ctx.cr2)
+            && region.kind == RegionKind::Guard
+        {
+            match region.owner {
+                Some(owner) => writeln!(w, "stack overflow in task {}", owner)?,
+                None => writeln!(w, "stack overflow into the guard page of {}", region.name)?,
+            }
+        }
+        ctx.dump(w)
+    })
+}
+
+unsafe extern "sysv64" {
+    /// Entries of exceptions. The entry of vector `v` starts at `v * FAULT_STUB_SIZE` bytes from
+    /// this, and pushes a dummy error code if the exception has none and `v`, then jumps to
+    /// `fault_common`.
+    fn fault_stubs();
+}
+
+global_asm! { r#"
+.macro fault_stub vector, has_error
+    .balign {stub_size}
+    .if \has_error == 0
+    push 0
+    .endif
+    push \vector
+    jmp fault_common
+.endm
+
+.balign {stub_size}
+.global fault_stubs
+fault_stubs:
+    fault_stub 0, 0
+    fault_stub 1, 0
+    fault_stub 2, 0
+    fault_stub 3, 0
+    fault_stub 4, 0
+    fault_stub 5, 0
+    fault_stub 6, 0
+    fault_stub 7, 0
+    fault_stub 8, 1
+    fault_stub 9, 0
+    fault_stub 10, 1
+    fault_stub 11, 1
+    fault_stub 12, 1
+    fault_stub 13, 1
+    fault_stub 14, 1
+    fault_stub 15, 0
+    fault_stub 16, 0
+    fault_stub 17, 1
+    fault_stub 18, 0
+    fault_stub 19, 0
+    fault_stub 20, 0
+    fault_stub 21, 1
+
+.global fault_common
+fault_common:
+    # Construct `FaultContext` on the stack.
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    # Save CR2 before a nested page fault overwrites it.
+    mov rax, cr2
+    push rax
+
+    mov rdi, rsp
+    # Align RSP to 16 bytes, keeping the original one in the callee-saved RBX.
+    mov rbx, rsp
+    and rsp, 0xfffffffffffffff0
+    cld
+    call _fault_dispatch
+    mov rsp, rbx
+
+    # Discard CR2.
+    add rsp, 0x08
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    # Discard the vector and the error code.
+    add rsp, 0x10
+    iretq
+"#,
+    stub_size = const FAULT_STUB_SIZE,
+}
+
+/// Handler that can be registered to a vector with [`register_handler()`].
+pub type Handler = Box<dyn Fn(&InterruptFrame) + Send + Sync>;
+
+/// Identifies a handler registered with [`register_handler()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    /// Vector the handler is registered to.
+    vector: u8,
+    /// Unique number among all handlers.
+    id: u32,
+}
+
+impl HandlerId {
+    /// Returns the vector the handler is registered to.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+/// Table of handlers for dynamically allocated vectors.
+static HANDLERS: InterruptFreeMutex<HandlerTable> = InterruptFreeMutex::new(HandlerTable::new());
+
+/// Holds handlers for each dynamically allocatable vector.
+struct HandlerTable {
+    /// Entries for vectors from [`DYNAMIC_VEC_START`] to [`DYNAMIC_VEC_END`].
+    vectors: [VectorEntry; DYNAMIC_VEC_COUNT],
+    /// Id that will be assigned to the next registered handler.
+    next_id: u32,
+}
+
+impl HandlerTable {
+    const fn new() -> Self {
+        Self {
+            vectors: [const { VectorEntry::new() }; DYNAMIC_VEC_COUNT],
+            next_id: 0,
+        }
+    }
+
+    /// Returns the entry for `vector` if it is dynamically allocatable.
+    fn get_mut(&mut self, vector: u8) -> Option<&mut VectorEntry> {
+        self.vectors
+            .get_mut(vector.checked_sub(DYNAMIC_VEC_START)? as usize)
+    }
+
+    /// Returns the entry for `vector` if it is allocated by [`allocate_vector()`].
+    fn get_allocated_mut(&mut self, vector: u8) -> Result<&mut VectorEntry> {
+        match self.get_mut(vector) {
+            Some(entry) if entry.allocated => Ok(entry),
+            _ => error!(format!("vector {:#x} is not allocated", vector)),
+        }
+    }
+}
+
+/// State of a dynamically allocatable vector.
+struct VectorEntry {
+    /// Whether the vector is allocated by [`allocate_vector()`].
+    allocated: bool,
+    /// Handlers called in registration order when an interrupt of the vector occurs.
+    handlers: Vec<(u32, Handler)>,
+}
+
+impl VectorEntry {
+    const fn new() -> Self {
+        Self {
+            allocated: false,
+            handlers: Vec::new(),
+        }
+    }
+}
+
+/// Allocates a vector that no one uses and returns it.
+pub fn allocate_vector() -> Result<u8> {
+    let mut table = HANDLERS.lock();
+    let Some(index) = table.vectors.iter().position(|entry| !entry.allocated) else {
+        error!("no free interrupt vector");
+    };
+    table.vectors[index].allocated = true;
+    Ok(DYNAMIC_VEC_START + index as u8)
+}
+
+/// Frees `vector` allocated by [`allocate_vector()`]. All handlers registered to `vector` must be
+/// unregistered before.
+pub fn free_vector(vector: u8) -> Result<()> {
+    let mut table = HANDLERS.lock();
+    let entry = table.get_allocated_mut(vector)?;
+    if !entry.handlers.is_empty() {
+        error!(format!("vector {:#x} still has handlers", vector));
+    }
+    entry.allocated = false;
+    Ok(())
+}
+
+/// Registers `handler` to `vector` allocated by [`allocate_vector()`]. More than one handler can
+/// be registered to the same vector, and they are called in registration order.
+///
+/// Handlers run with interrupts disabled, and must not call [`register_handler()`] or
+/// [`unregister_handler()`] because it causes deadlock. End of interrupt is notified after all
+/// handlers return.
+pub fn register_handler(
+    vector: u8,
+    handler: impl Fn(&InterruptFrame) + Send + Sync + 'static,
+) -> Result<HandlerId> {
+    let mut table = HANDLERS.lock();
+    let id = table.next_id;
+    let entry = table.get_allocated_mut(vector)?;
+    entry.handlers.push((id, Box::new(handler)));
+    table.next_id += 1;
+    Ok(HandlerId { vector, id })
+}
+
+/// Unregisters the handler identified by `id`.
+pub fn unregister_handler(id: HandlerId) -> Result<()> {
+    let mut table = HANDLERS.lock();
+    let entry = table.get_allocated_mut(id.vector)?;
+    let Some(index) = entry.handlers.iter().position(|&(i, _)| i == id.id) else {
+        error!(format!("handler {:?} is not registered", id));
+    };
+    // Keep registration order of the other handlers.
+    drop(entry.handlers.remove(index));
+    Ok(())
+}
+
+/// Calls handlers registered to `vector`. Called by `int_dispatch_common`, which calculates the
+/// vector from the return address pushed by a stub in [`int_dispatch_stubs`].
+#[unsafe(no_mangle)]
+extern "sysv64" fn _int_dispatch(frame: &InterruptFrame, vector: u64) {
+    {
+        let mut table = HANDLERS.lock();
+        match table.get_mut(vector as _) {
+            Some(entry) if !entry.handlers.is_empty() => {
+                for (_, handler) in &entry.handlers {
+                    handler(frame);
+                }
+            }
+            _ => log::warn!("unhandled interrupt: vector {:#x}", vector),
+        }
+    }
+    apic::notify_end_of_interrupt();
+}
+
+unsafe extern "sysv64" {
+    /// Entries of dynamically allocatable vectors. The entry of vector `v` starts at
+    /// `(v - DYNAMIC_VEC_START) * DISPATCH_STUB_SIZE` bytes from this, and just calls
+    /// `int_dispatch_common`.
+    fn int_dispatch_stubs();
+}
+
+global_asm! { r#"
+.balign {stub_size}
+.global int_dispatch_stubs
+int_dispatch_stubs:
+.rept {count}
+    .balign {stub_size}
+    call int_dispatch_common
+.endr
+
+.global int_dispatch_common
+int_dispatch_common:
+    push rbp
+    mov rbp, rsp
+
+    # Adjust the RSP to 16-byte align.
+    # It will align RSP properly because pushing 10, even words after this.
+    and rsp, 0xfffffffffffffff0
+
+    push rax
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    cld
+
+    # The return address is `int_dispatch_stubs + index * stub_size + (size of call)`.
+    mov rsi, [rbp + 0x08]
+    lea rax, [rip + int_dispatch_stubs]
+    sub rsi, rax
+    shr rsi, {stub_shift}
+    add rsi, {start}
+    lea rdi, [rbp + 0x10]
+    call _int_dispatch
+
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop rax
+    mov rsp, rbp
+    pop rbp
+    # Discard the return address pushed by the stub.
+    add rsp, 0x08
+    iretq
+"#,
+    stub_size = const DISPATCH_STUB_SIZE,
+    stub_shift = const DISPATCH_STUB_SIZE.trailing_zeros(),
+    count = const DYNAMIC_VEC_COUNT,
+    start = const DYNAMIC_VEC_START,
+}
+
+unsafe extern "sysv64" {
+    /// Ignores spurious interrupts, which require no EOI.
+    fn int_handler_spurious();
+    /// Saves registers that callers save, and calls
+    /// [`_int_handler_softirq()`](crate::softirq::_int_handler_softirq).
+    fn int_handler_softirq();
+    /// Same as `int_handler_softirq` but calls
+    /// [`_int_handler_tlb_flush()`](crate::paging::_int_handler_tlb_flush).
+    fn int_handler_tlb_flush();
+}
+
+global_asm! { r#"
+.global int_handler_spurious
+int_handler_spurious:
+    iretq
+
+.macro CALLER_SAVED_INT_HANDLER name, handler
+.global \name
+\name:
+    push rbp
+    mov rbp, rsp
+
+    # Align RSP to 16 bytes in the same way as `int_dispatch_common`.
+    and rsp, 0xfffffffffffffff0
+
+    push rax
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    cld
+
+    call \handler
+
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop rax
+    mov rsp, rbp
+    pop rbp
+    iretq
+.endm
+
+CALLER_SAVED_INT_HANDLER int_handler_softirq, _int_handler_softirq
+CALLER_SAVED_INT_HANDLER int_handler_tlb_flush, _int_handler_tlb_flush
+"# }
 
 unsafe extern "sysv64" {
     /// Saves context before interrupt, and call [`_int_handler_tiemr`] with an argument, the
     /// reference to the context.
     fn int_handler_timer();
+    /// Same as `int_handler_timer` but calls
+    /// [`_int_handler_resched()`](crate::task::_int_handler_resched).
+    fn int_handler_resched();
 }
 
 global_asm! { r#"
-.global int_handler_timer
-int_handler_timer:
+.macro CONTEXT_INT_HANDLER name, handler
+.global \name
+\name:
     cli
     push rbp
     mov rbp, rsp
@@ -228,7 +721,7 @@ int_handler_timer:
 
     # Pass the reference to previous context as the first argument.
     lea rdi, [rsp]
-    call _int_handler_timer
+    call \handler
 
     # Discard up to GS
     add rsp, 8 * 8
@@ -255,4 +748,8 @@ int_handler_timer:
     mov rsp, rbp
     pop rbp
     iretq
+.endm
+
+CONTEXT_INT_HANDLER int_handler_timer, _int_handler_timer
+CONTEXT_INT_HANDLER int_handler_resched, _int_handler_resched
 "# }
@@ -4,14 +4,25 @@
 #![deny(improper_ctypes_definitions)]
 
 pub mod acpi;
+pub mod cpu;
+pub mod crash;
 pub mod driver;
+pub mod executor;
+pub mod fpu;
+pub mod gdt;
 pub mod interrupt;
 pub mod logger;
 pub mod memmap;
 pub mod paging;
 pub mod screen;
+pub mod serial;
+pub mod smp;
+pub mod softirq;
+pub mod symbol;
 pub mod sync;
 pub mod task;
 pub mod timer;
+pub mod tls;
+pub mod workqueue;
 
 pub extern crate alloc;
@@ -2,9 +2,9 @@
 
 use alloc::{collections::vec_deque::VecDeque, format, string::String};
 use log::{Level, LevelFilter, Log};
-use util::{asmfunc, error, error::Result, sync::InterruptFreeMutex};
+use util::{error, error::Result, sync::InterruptFreeMutex};
 
-use crate::timer;
+use crate::{serial, timer};
 
 /// [Logger] for kernel.
 static LOGGER: Logger = Logger {
@@ -36,8 +36,6 @@ impl Log for Logger {
     fn flush(&self) {}
 
     fn log(&self, record: &log::Record) {
-        use util::bitfield::BitField as _;
-
         if !self.enabled(record.metadata()) {
             return;
         }
@@ -57,24 +55,18 @@ impl Log for Logger {
         // 3. Log service prints logs through fb service.
         {
             const UART_MAX_LEVEL: Level = Level::Info;
-            const UART_BASE_PORT: u16 = 0x3f8;
-            const LINE_PORT: u16 = UART_BASE_PORT + 5;
 
             let micros = entry.time_stamp % 1_000_000_000 / 1_000;
             let secs = entry.time_stamp / 1_000_000_000;
 
             if entry.level <= UART_MAX_LEVEL {
-                for b in format!(
-                    "[{:6}.{:06}, {:>5}] ({}) {}\n",
-                    secs, micros, entry.level, entry.module, entry.content,
-                )
-                .as_bytes()
-                {
-                    while !asmfunc::io_inb(LINE_PORT).get_bit(5) {
-                        core::hint::spin_loop();
-                    }
-                    asmfunc::io_outb(UART_BASE_PORT, *b);
-                }
+                serial::write_bytes(
+                    format!(
+                        "[{:6}.{:06}, {:>5}] ({}) {}\n",
+                        secs, micros, entry.level, entry.module, entry.content,
+                    )
+                    .as_bytes(),
+                );
             }
         }
 
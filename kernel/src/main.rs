@@ -15,14 +15,11 @@ use log::info;
 use task::TASK_MANAGER;
 use uefi::table::{Runtime, SystemTable, boot::MemoryMap};
 use util::{
-    asmfunc,
-    buffer::StrBuf,
-    descriptor::{self, GDT, SegmentDescriptor, SegmentType, SystemDescriptor},
+    apic, asmfunc,
+    elf::{SymbolTableInfo, TlsTemplate},
     error::Result,
     graphics::GrayscalePrint as _,
-    paging::PAGE_SIZE,
     screen::{FrameBufferInfo, Screen},
-    sync::OnceStatic,
 };
 
 use {memmap::PAGE_MAP, screen::FB_INFO};
@@ -39,19 +36,28 @@ core::arch::global_asm! { r#"
 _start:
     lea rsp, [KERNEL_STACK + rip]
     add rsp, 1 * 1024 * 1024
+    # Terminate the chain of frame pointers for backtraces.
+    xor ebp, ebp
     call main
 "#
 }
 
-/// Global TSS.
-static TSS: OnceStatic<descriptor::TSS> = OnceStatic::new();
-
 #[unsafe(no_mangle)]
-fn main(fb_info: &FrameBufferInfo, memmap: &'static mut MemoryMap, runtime: SystemTable<Runtime>) {
+fn main(
+    fb_info: &FrameBufferInfo,
+    memmap: &'static mut MemoryMap,
+    runtime: SystemTable<Runtime>,
+    symbols: &SymbolTableInfo,
+    tls: &TlsTemplate,
+) {
+    // Copy them because they are not accessible after dropping the identical mapping.
+    let symbols = *symbols;
+    let tls = *tls;
     // Safety: There is one processor running and this is the first time to initialize.
     //   There is only `fb_info` that uses first half parts of virtual address. So, all we have to
     //   do is just mapping it properly.
     let runtime = unsafe { PAGE_MAP.init(memmap, runtime) };
+    symbol::init(&symbols);
     let fb_info = FrameBufferInfo {
         frame_buffer: paging::pyhs_to_virt(fb_info.frame_buffer as _)
             .unwrap()
@@ -60,7 +66,7 @@ fn main(fb_info: &FrameBufferInfo, memmap: &'static mut MemoryMap, runtime: Syst
     };
     FB_INFO.init(fb_info);
 
-    match main2(runtime) {
+    match main2(runtime, tls) {
         Ok(_) => unreachable!(),
         Err(e) => {
             let msg = format!("{}", e);
@@ -75,55 +81,35 @@ fn main(fb_info: &FrameBufferInfo, memmap: &'static mut MemoryMap, runtime: Syst
 }
 
 // NOTE: Never return `Ok()`.
-fn main2(runtime: SystemTable<Runtime>) -> Result<()> {
+fn main2(runtime: SystemTable<Runtime>, tls: TlsTemplate) -> Result<()> {
     logger::init()?;
     info!("===== main2 started =====");
 
-    let stack_for_timer_interrupt = PAGE_MAP.allocate(2);
-    if stack_for_timer_interrupt.is_null() {
-        panic!("Failed to allocate 2 pages");
-    }
-
-    // Set a new GDT for kernel.
-    let mut gdt = GDT::new(5);
-    gdt.set(1, SegmentDescriptor::new(SegmentType::code(true, false), 0))?;
-    gdt.set(2, SegmentDescriptor::new(SegmentType::data(true, false), 0))?;
-
-    TSS.init(descriptor::TSS::new(
-        &[],
-        &[unsafe { stack_for_timer_interrupt.byte_add(2 * PAGE_SIZE) } as _],
-    ));
-    gdt.set(3, SystemDescriptor::new_tss(TSS.as_ref(), 0))?;
-
-    gdt.register();
-    asmfunc::set_cs_ss(1 << 3, 2 << 3);
-    asmfunc::set_ds_all(0);
-    asmfunc::load_tr(3 << 3);
+    gdt::init()?;
+    cpu::init(0, apic::local_apic_id());
+    fpu::init();
+    tls::init(tls);
 
     screen::init();
     interrupt::init()?;
+    paging::init_tlb_shootdown();
     acpi::init(runtime)?;
 
     driver::init()?;
 
     timer::init()?;
     TASK_MANAGER.init();
+    smp::init()?;
+    workqueue::init()?;
+    executor::init()?;
     TASK_MANAGER.start();
 }
 
 #[panic_handler]
 fn _panic_handler(info: &core::panic::PanicInfo) -> ! {
-    asmfunc::cli();
-    if FB_INFO.is_initialized() {
-        let mut screen = Screen::new(FB_INFO.as_ref().clone());
-        screen.clear();
-        let mut buf = [0; 4 * 1024];
-        let mut buf = StrBuf::new(&mut buf);
-        let _ = write!(buf, "{:#}", info);
-        screen.print_str(buf.to_str(), (0, 0))
-    }
-
-    loop {
-        asmfunc::hlt();
-    }
+    let regs = crash::Registers::capture();
+    crash::die(|w| {
+        writeln!(w, "{:#}", info)?;
+        crash::dump(w, &regs)
+    })
 }
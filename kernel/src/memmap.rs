@@ -15,7 +15,7 @@ use uefi::table::{
 };
 use util::{
     paging::{PAGE_SIZE, PageEntry},
-    sync::InterruptFreeMutex,
+    sync::{InterruptFreeMutex, OnceStatic},
 };
 
 use crate::paging::{self, KERNEL_PML4};
@@ -29,6 +29,16 @@ pub const MAX_ORDER: usize = 10;
 /// Number of caches stored in [`Global`] allocator.
 const NUM_CHACHES: usize = (PAGE_SIZE.trailing_zeros() - WORD_SIZE.trailing_zeros()) as usize;
 
+/// Number of pages reserved below 1 MiB by [`PageMap::init()`].
+pub const LOW_PAGES_COUNT: usize = 4;
+
+/// Physical address of [`LOW_PAGES_COUNT`] pages below 1 MiB, which are never allocated.
+/// Application processors start in real mode, which can only run code there.
+pub static LOW_PAGES: OnceStatic<u64> = OnceStatic::new();
+
+/// The end of memory addressable in real mode.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
 /// [PageMap] for this kernel.
 pub static PAGE_MAP: PageMap = PageMap {
     table: UnsafeCell::new([
@@ -216,16 +226,33 @@ impl PageMap {
                 continue;
             }
 
+            let mut desc_start = desc.virt_start;
+            let mut desc_page_count = desc.page_count as usize;
+            // Set aside the first low pages found, except page 0.
+            let low_pages_start = desc.phys_start.max(PAGE_SIZE as u64);
+            let skipped_count = ((low_pages_start - desc.phys_start) / PAGE_SIZE as u64) as usize;
+            let low_pages_end = low_pages_start + (LOW_PAGES_COUNT * PAGE_SIZE) as u64;
+            if !LOW_PAGES.is_initialized()
+                && desc_page_count >= skipped_count + LOW_PAGES_COUNT
+                && low_pages_end <= LOW_MEMORY_END
+            {
+                LOW_PAGES.init(low_pages_start);
+                // Page 0 is not allocated either.
+                let reserved_count = skipped_count + LOW_PAGES_COUNT;
+                desc_start += (reserved_count * PAGE_SIZE) as u64;
+                desc_page_count -= reserved_count;
+            }
+
             // If `desc` is the continuation of the block considering, connect them.
-            if block_start + (page_count * PAGE_SIZE) as u64 == desc.virt_start {
-                page_count += desc.page_count as usize;
+            if block_start + (page_count * PAGE_SIZE) as u64 == desc_start {
+                page_count += desc_page_count;
                 continue;
             }
 
             self.register_continuous_pages(block_start, page_count, true);
 
-            block_start = desc.virt_start;
-            page_count = desc.page_count as _;
+            block_start = desc_start;
+            page_count = desc_page_count;
         }
 
         self.register_continuous_pages(block_start, page_count, true);
@@ -1,14 +1,30 @@
 //! Provides some useful items to control paging.
 
-use core::mem;
+use core::{
+    hint, mem,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering::*},
+};
 
+use alloc::{format, vec::Vec};
 use util::paging::{ADDRESS_CONVERTER, AddressConverter, PAGE_SIZE, PageTable, VirtualAddress};
 use util::{
-    asmfunc,
+    apic::{self, DeliveryMode},
+    asmfunc, error,
+    error::Result,
+    interrupt::PageFaultErrorCode,
     paging::PageEntry,
     sync::{InterruptFreeMutex, OnceStatic},
 };
 
+use crate::{
+    cpu::{self, PerCpu},
+    interrupt::TLB_FLUSH_INT_VEC,
+    memmap::PAGE_MAP,
+    task::TaskId,
+};
+
 /// Base address to which kernel map whole physical address.
 pub const STRAIGHT_PAGE_MAP_BASE: VirtualAddress = VirtualAddress::new(0xffff_8000_0000_0000);
 
@@ -16,6 +32,12 @@ pub const STRAIGHT_PAGE_MAP_BASE: VirtualAddress = VirtualAddress::new(0xffff_80
 /// [`STRAIGHT_PAGE_SIZE`], this is the max physical memory size of kernel.
 pub const STRAIGHT_PAGE_SIZE: u64 = 1 << (12 + 9 * 3);
 
+/// Base address of the space where pages are mapped dynamically by 4 KiB. See [`reserve()`].
+pub const DYNAMIC_MAP_BASE: VirtualAddress = VirtualAddress::new(0xffff_c000_0000_0000);
+
+/// Size of the dynamic map space.
+pub const DYNAMIC_MAP_SIZE: u64 = 1 << 45;
+
 unsafe extern "C" {
     /// Placed at the start of the kernel.
     static _kernel_start: core::ffi::c_void;
@@ -51,6 +73,12 @@ static STRAIGHT_PDS: InterruptFreeMutex<[PageTable; 512]> =
 static STRAIGHT_PT_FOR_REMAINDERS: InterruptFreeMutex<PageTable> =
     InterruptFreeMutex::new(PageTable::new());
 
+/// Regions reserved in the dynamic map space.
+static DYNAMIC_MAP: InterruptFreeMutex<DynamicMap> = InterruptFreeMutex::new(DynamicMap {
+    regions: Vec::new(),
+    next: DYNAMIC_MAP_BASE.addr,
+});
+
 /// Initialize straight mapping of physical address `0` to virtual address
 /// [`STRAIGHT_PAGE_MAP_BASE`] with size [`STRAIGHT_PAGE_SIZE`] for kernel. The physical memory
 /// space where the kernel is located are excluded to avoid overwrite the content.
@@ -59,11 +87,8 @@ pub fn init_straight_mapping() {
     KERNEL_VIRT_END.init(VirtualAddress::new(&raw const _kernel_end as _));
 
     // Safety: CR3 is always valid as a pointer of PageTable because if not, processor does not
-    //     work.
-    KERNEL_PML4.init(InterruptFreeMutex::new(unsafe {
-        &mut *(asmfunc::get_cr3() as *mut PageTable)
-    }));
-    let mut pml4 = KERNEL_PML4.as_ref().lock();
+    //     work. Physical addresses are still identically mapped here.
+    let pml4 = unsafe { &mut *((asmfunc::get_cr3() & !0xfff) as *mut PageTable) };
     #[rustfmt::skip]
     // Safety: Page map for kernel defenitely exists because if not, kernel does not properly work.
     let kernel_phys_base = unsafe {
@@ -143,6 +168,13 @@ pub fn init_straight_mapping() {
         )
     };
 
+    // Access PML4 through the straight mapping from now on because the identical mapping is
+    // dropped after the initialization of the memory map.
+    // Safety: CR3 is in the straight mapping just constructed.
+    KERNEL_PML4.init(InterruptFreeMutex::new(unsafe {
+        &mut *(pyhs_to_virt(asmfunc::get_cr3() & !0xfff).unwrap().addr as *mut PageTable)
+    }));
+
     ADDRESS_CONVERTER.init(AddressConverter::new(phys_to_virt2));
 }
 
@@ -177,3 +209,482 @@ pub fn virt_to_phys(addr: impl Into<VirtualAddress>) -> Option<u64> {
 fn phys_to_virt2(addr: u64) -> u64 {
     pyhs_to_virt(addr).map(|addr| addr.addr).unwrap_or(0)
 }
+
+/// Represents how pages in a [`Region`] are mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// All pages are mapped on reservation.
+    Eager { writable: bool },
+    /// Each page is mapped on the first access to it.
+    Lazy { writable: bool },
+    /// No page is mapped. This is used to detect overruns of the neighboring regions.
+    Guard,
+}
+
+/// Region in the dynamic map space reserved by [`reserve()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    /// Start address of the region.
+    pub start: u64,
+    /// End address of the region (exclusive).
+    pub end: u64,
+    /// How pages in the region are mapped.
+    pub kind: RegionKind,
+    /// Name of the region for diagnostics.
+    pub name: &'static str,
+    /// Task owning the region, if any.
+    pub owner: Option<TaskId>,
+}
+
+impl Region {
+    /// Returns whether the region contains `addr`.
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+/// Manages the dynamic map space.
+struct DynamicMap {
+    /// Reserved regions sorted by their start addresses.
+    regions: Vec<Region>,
+    /// Start address of the next reservation.
+    // NOTE: Released space is not reused. The space is large enough to reserve regions for the
+    //       lifetime of the kernel.
+    next: u64,
+}
+
+impl DynamicMap {
+    /// Reserves `page_count` pages as a region of `kind` named `name`, and returns its start
+    /// address.
+    fn reserve(
+        &mut self,
+        page_count: usize,
+        kind: RegionKind,
+        name: &'static str,
+        owner: Option<TaskId>,
+    ) -> Result<u64> {
+        let size = (page_count * PAGE_SIZE) as u64;
+        if size == 0 || DYNAMIC_MAP_BASE.addr + DYNAMIC_MAP_SIZE - self.next < size {
+            error!(format!(
+                "failed to reserve {} pages for {}",
+                page_count, name
+            ));
+        }
+
+        let start = self.next;
+        let end = start + size;
+        if let RegionKind::Eager { writable } = kind {
+            for addr in (start..end).step_by(PAGE_SIZE) {
+                if let Err(e) = map_new_page(addr, writable) {
+                    // The pages have not been accessed by other processors, so they need no
+                    // shootdown, which would wait for processors spinning on this lock.
+                    for addr in (start..addr).step_by(PAGE_SIZE) {
+                        if let Some(phys) = unmap_page(addr) {
+                            // Safety: the page is not used by anyone yet.
+                            unsafe { free_frame(phys) };
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        self.next = end;
+        self.regions.push(Region {
+            start,
+            end,
+            kind,
+            name,
+            owner,
+        });
+        Ok(start)
+    }
+
+    /// Returns the region containing `addr`.
+    fn find(&self, addr: u64) -> Option<Region> {
+        let index = self.regions.partition_point(|region| region.end <= addr);
+        self.regions
+            .get(index)
+            .filter(|region| region.contains(addr))
+            .copied()
+    }
+}
+
+/// Represents a result of [`handle_page_fault()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFault {
+    /// The page is now mapped, so the faulting access can be retried.
+    Resolved,
+    /// The address is in the guard region.
+    Guard(Region),
+    /// The fault is not resolvable. Holds the region containing the address if any.
+    Invalid(Option<Region>),
+}
+
+/// Reserves `page_count` pages in the dynamic map space as a region of `kind` named `name`, and
+/// returns its start address.
+pub fn reserve(page_count: usize, kind: RegionKind, name: &'static str) -> Result<u64> {
+    DYNAMIC_MAP.lock().reserve(page_count, kind, name, None)
+}
+
+/// Reserves a writable stack of `page_count` pages named `name` with an unmapped guard page
+/// below it, and returns the range of the stack. Overflowing the stack causes a page fault
+/// reported as [`PageFault::Guard`] whose owner is `owner`.
+pub fn reserve_stack(
+    page_count: usize,
+    name: &'static str,
+    owner: Option<TaskId>,
+) -> Result<Range<u64>> {
+    let mut map = DYNAMIC_MAP.lock();
+    // Reserve both while locked to place them contiguously.
+    map.reserve(1, RegionKind::Guard, name, owner)?;
+    let start = map.reserve(
+        page_count,
+        RegionKind::Eager { writable: true },
+        name,
+        owner,
+    )?;
+    Ok(start..start + (page_count * PAGE_SIZE) as u64)
+}
+
+/// Releases the region starting at `start` reserved by [`reserve()`], and returns it. Pages mapped
+/// in the region are unmapped and freed after flushing TLBs of all processors by
+/// [`flush_tlb_all()`], so do not call this holding an [`InterruptFreeMutex`].
+///
+/// # Safety
+///
+/// The memory in the region must not be accessed after releasing.
+pub unsafe fn release(start: u64) -> Result<Region> {
+    let region = {
+        let mut map = DYNAMIC_MAP.lock();
+        let Ok(index) = map
+            .regions
+            .binary_search_by_key(&start, |region| region.start)
+        else {
+            error!(format!("no region starts at {:#x}", start));
+        };
+        map.regions.remove(index)
+    };
+    // Released space is not reused, so the pages can be unmapped after unlocking.
+    // Safety: the caller guarantees the memory is no longer used.
+    unsafe { unmap_and_free(region.start..region.end) };
+    Ok(region)
+}
+
+/// Releases the stack reserved by [`reserve_stack()`] with its guard page. Do not call this
+/// holding an [`InterruptFreeMutex`] in the same way as [`release()`].
+///
+/// # Safety
+///
+/// The stack must not be used after releasing.
+pub unsafe fn release_stack(stack: Range<u64>) -> Result<()> {
+    // Safety: the caller guarantees the stack is no longer used.
+    unsafe {
+        release(stack.start)?;
+        release(stack.start - PAGE_SIZE as u64)?;
+    }
+    Ok(())
+}
+
+/// Returns the region in the dynamic map space containing `addr`.
+pub fn find_region(addr: u64) -> Option<Region> {
+    DYNAMIC_MAP.lock().find(addr)
+}
+
+/// Returns the region containing `addr` like [`find_region()`], or `None` if the regions are
+/// locked. This is intended to be called from fault handlers, which may interrupt the holder.
+pub fn try_find_region(addr: u64) -> Option<Region> {
+    DYNAMIC_MAP.try_lock()?.find(addr)
+}
+
+/// Tries to resolve a page fault on accessing `addr`, and returns how it is handled. Only
+/// accesses to non-present pages in [`RegionKind::Lazy`] regions can be resolved.
+///
+/// This is intended to be called from the page fault handler.
+pub fn handle_page_fault(addr: u64, error: PageFaultErrorCode) -> PageFault {
+    // Avoid a deadlock when the fault occurs while the dynamic map is locked.
+    let Some(map) = DYNAMIC_MAP.try_lock() else {
+        return PageFault::Invalid(None);
+    };
+    let Some(region) = map.find(addr) else {
+        return PageFault::Invalid(None);
+    };
+
+    match region.kind {
+        RegionKind::Guard => PageFault::Guard(region),
+        RegionKind::Lazy { writable }
+            if !error.present() && !error.user() && (writable || !error.write()) =>
+        {
+            let page = addr & !(PAGE_SIZE as u64 - 1);
+            // Another processor may have already mapped the page.
+            if translate(page).is_some() || map_new_page(page, writable).is_ok() {
+                PageFault::Resolved
+            } else {
+                PageFault::Invalid(Some(region))
+            }
+        }
+        _ => PageFault::Invalid(Some(region)),
+    }
+}
+
+/// Maps the 4-KiB page at `virt` to the physical page at `phys` in [`KERNEL_PML4`]. Page tables
+/// are allocated from [`PAGE_MAP`] if needed.
+///
+/// Returns `Err` if the page is already mapped or page tables cannot be allocated.
+pub fn map_page(virt: impl Into<VirtualAddress>, phys: u64, writable: bool) -> Result<()> {
+    let virt: VirtualAddress = virt.into();
+    let mut pml4 = KERNEL_PML4.as_ref().lock();
+
+    let mut table: &mut PageTable = &mut pml4;
+    for level in (2..=4).rev() {
+        let entry = &mut table[virt.get_level_index(level)];
+        if !entry.present() {
+            let new_table = PAGE_MAP.allocate(1);
+            if new_table.is_null() {
+                error!(format!(
+                    "failed to allocate a page table to map {:#x}",
+                    virt.addr
+                ));
+            }
+            // Safety: `new_table` is a newly allocated page.
+            unsafe { ptr::write_bytes(new_table, 0, PAGE_SIZE) };
+            // Safety: `new_table` is a page in the straight mapping. Page tables of the kernel
+            //     space are not accessible from user mode.
+            *entry =
+                unsafe { PageEntry::new(virt_to_phys(new_table as u64).unwrap(), true, false) };
+        }
+        let Some(next) = next_table(entry) else {
+            error!(format!(
+                "{:#x} is already mapped as a large page",
+                virt.addr
+            ));
+        };
+        table = next;
+    }
+
+    let entry = &mut table[virt.pt_index()];
+    if entry.present() {
+        error!(format!("{:#x} is already mapped", virt.addr));
+    }
+    // Safety: the caller passes a 4-KiB aligned physical page.
+    *entry = unsafe { PageEntry::new(phys, writable, false) };
+    Ok(())
+}
+
+/// Unmaps the 4-KiB page at `virt` from [`KERNEL_PML4`], and returns the physical address which
+/// was mapped. Page tables are not freed even if they become empty.
+///
+/// Only the TLB of the current processor is flushed. Call [`flush_tlb_all()`] before reusing the
+/// returned page if other processors may have accessed it.
+pub fn unmap_page(virt: impl Into<VirtualAddress>) -> Option<u64> {
+    let virt: VirtualAddress = virt.into();
+    let mut pml4 = KERNEL_PML4.as_ref().lock();
+
+    let mut table: &mut PageTable = &mut pml4;
+    for level in (2..=4).rev() {
+        table = next_table(&table[virt.get_level_index(level)])?;
+    }
+    let entry = &mut table[virt.pt_index()];
+    if !entry.present() {
+        return None;
+    }
+    // Safety: `entry` is present.
+    let phys = unsafe { entry.next_addr() };
+    *entry = PageEntry::null();
+    asmfunc::invlpg(virt.addr);
+    Some(phys)
+}
+
+/// Translates `virt` into the physical address by walking [`KERNEL_PML4`]. Returns `None` if it
+/// is not mapped.
+pub fn translate(virt: impl Into<VirtualAddress>) -> Option<u64> {
+    walk(&KERNEL_PML4.as_ref().lock(), virt.into())
+}
+
+/// Translates `virt` into the physical address by walking the page tables referenced by CR3
+/// without locking [`KERNEL_PML4`]. Returns `None` if it is not mapped.
+///
+/// This is intended for crash paths, where the lock may be held by the crashed code.
+///
+/// # Safety
+///
+/// The page tables must not be modified concurrently.
+pub unsafe fn translate_unlocked(virt: impl Into<VirtualAddress>) -> Option<u64> {
+    let pml4 = pyhs_to_virt(asmfunc::get_cr3() & !0xfff)?.addr;
+    // Safety: CR3 references PML4 and the caller guarantees no one modifies it.
+    walk(unsafe { &*(pml4 as *const PageTable) }, virt.into())
+}
+
+/// Translates `virt` into the physical address by walking page tables from `pml4`.
+fn walk(pml4: &PageTable, virt: VirtualAddress) -> Option<u64> {
+    let mut entry = pml4[virt.pml4_index()];
+    for level in (1..=3).rev() {
+        entry = next_table(&entry)?[virt.get_level_index(level)];
+        if !entry.present() {
+            return None;
+        }
+        if entry.page_size() || level == 1 {
+            let mask = (1 << (12 + 9 * (level - 1))) - 1;
+            // Safety: `entry` is present.
+            return Some((unsafe { entry.next_addr() } & !mask) | (virt.addr & mask));
+        }
+    }
+    unreachable!()
+}
+
+/// Returns the page table referenced by `entry` through the straight mapping. Returns `None` if
+/// `entry` is not present or maps a large page.
+///
+/// The returned table must be accessed only while [`KERNEL_PML4`] is locked.
+fn next_table(entry: &PageEntry) -> Option<&'static mut PageTable> {
+    if !entry.present() || entry.page_size() {
+        return None;
+    }
+    // Safety: `entry` is present and references a page table.
+    let addr = pyhs_to_virt(unsafe { entry.next_addr() })?.addr;
+    // Safety: page tables are in the straight mapping.
+    Some(unsafe { &mut *(addr as *mut PageTable) })
+}
+
+/// Allocates a zeroed page from [`PAGE_MAP`] and maps it at `virt`.
+fn map_new_page(virt: u64, writable: bool) -> Result<()> {
+    let page = PAGE_MAP.allocate(1);
+    if page.is_null() {
+        error!(format!("failed to allocate a page to map {:#x}", virt));
+    }
+    // Safety: `page` is a newly allocated page.
+    unsafe { ptr::write_bytes(page, 0, PAGE_SIZE) };
+
+    // Unwrapping succeeds because `page` is in the straight mapping.
+    let result = map_page(virt, virt_to_phys(page as u64).unwrap(), writable);
+    if result.is_err() {
+        // Safety: `page` is allocated above and not used.
+        unsafe { PAGE_MAP.free(page, 1) };
+    }
+    result
+}
+
+/// Unmaps pages in `range` and frees them into [`PAGE_MAP`] after flushing TLBs of all
+/// processors.
+///
+/// # Safety
+///
+/// Pages in `range` must be allocated by [`map_new_page()`] and no longer used.
+unsafe fn unmap_and_free(range: Range<u64>) {
+    /// The number of pages freed per shootdown.
+    const BATCH: usize = 64;
+
+    let mut frames = [0; BATCH];
+    let mut len = 0;
+    for addr in range.step_by(PAGE_SIZE) {
+        if let Some(phys) = unmap_page(addr) {
+            frames[len] = phys;
+            len += 1;
+        }
+        if len == BATCH {
+            // Safety: the caller guarantees the pages are no longer used.
+            unsafe { flush_and_free(&frames) };
+            len = 0;
+        }
+    }
+    // Safety: the caller guarantees the pages are no longer used.
+    unsafe { flush_and_free(&frames[..len]) };
+}
+
+/// Frees unmapped pages at `frames` after flushing TLBs of all processors.
+///
+/// # Safety
+///
+/// The pages must be allocated by [`map_new_page()`], unmapped and no longer used.
+unsafe fn flush_and_free(frames: &[u64]) {
+    if frames.is_empty() {
+        return;
+    }
+    flush_tlb_all();
+    for &phys in frames {
+        // Safety: no processor caches the translation to the page any longer.
+        unsafe { free_frame(phys) };
+    }
+}
+
+/// Frees the page at `phys` mapped by [`map_new_page()`] into [`PAGE_MAP`].
+///
+/// # Safety
+///
+/// The page must be unmapped and no longer used.
+unsafe fn free_frame(phys: u64) {
+    // Safety: the page was allocated from `PAGE_MAP` one by one.
+    unsafe { PAGE_MAP.free(pyhs_to_virt(phys).unwrap().addr as *mut u8, 1) };
+}
+
+/// [`PerCpu::tlb_generation()`] of processors which cannot receive TLB shootdowns yet.
+pub(crate) const TLB_NOT_READY: u64 = u64::MAX;
+
+/// Generation of TLB shootdowns, incremented by [`flush_tlb_all()`].
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Lets the current processor receive TLB shootdowns from [`flush_tlb_all()`]. Call this once on
+/// each processor after enabling its Local APIC and loading the IDT.
+pub fn init_tlb_shootdown() {
+    cpu::this_cpu()
+        .tlb_generation()
+        .store(TLB_GENERATION.load(SeqCst), SeqCst);
+    // Pages may have been freed after this processor accessed them and before this.
+    flush_tlb();
+}
+
+/// Flushes the TLB of the current processor. Global pages are not used, so reloading CR3 flushes
+/// all entries.
+fn flush_tlb() {
+    asmfunc::set_cr3(asmfunc::get_cr3());
+}
+
+/// Flushes TLBs of all processors, and waits until they finish, so that no processor translates
+/// addresses by page entries cleared before this.
+///
+/// Other processors flush in the handler of [`TLB_FLUSH_INT_VEC`], so do not call this holding
+/// an [`InterruptFreeMutex`] which they may be waiting for with interrupts disabled.
+pub fn flush_tlb_all() {
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    let generation = TLB_GENERATION.fetch_add(1, SeqCst) + 1;
+    flush_tlb();
+    if let Some(this) = cpu::try_this_cpu() {
+        this.tlb_generation().fetch_max(generation, SeqCst);
+        let others = || cpu::iter().filter(|cpu| cpu.id() != this.id());
+        for cpu in others() {
+            if cpu.tlb_generation().load(SeqCst) != TLB_NOT_READY {
+                apic::send_ipi(cpu.apic_id(), DeliveryMode::Fixed, TLB_FLUSH_INT_VEC);
+            }
+        }
+        for cpu in others() {
+            // Processors getting ready after the increment start with a newer generation.
+            while cpu.tlb_generation().load(SeqCst) < generation {
+                // Another processor may be waiting for this one in the same way.
+                handle_tlb_shootdown(this);
+                hint::spin_loop();
+            }
+        }
+    }
+    if if_is_set {
+        asmfunc::sti();
+    }
+}
+
+/// Flushes the TLB of `cpu`, the current processor, if a shootdown is requested.
+fn handle_tlb_shootdown(cpu: &PerCpu) {
+    // Load first so that the flush covers all page entries cleared before the generation.
+    let generation = TLB_GENERATION.load(SeqCst);
+    if cpu.tlb_generation().load(Relaxed) < generation {
+        flush_tlb();
+        cpu.tlb_generation().fetch_max(generation, SeqCst);
+    }
+}
+
+/// Flushes the TLB of the current processor for [`flush_tlb_all()`]. Called by
+/// `int_handler_tlb_flush` with interrupts disabled.
+#[unsafe(no_mangle)]
+extern "sysv64" fn _int_handler_tlb_flush() {
+    apic::notify_end_of_interrupt();
+    handle_tlb_shootdown(cpu::this_cpu());
+}
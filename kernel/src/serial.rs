@@ -0,0 +1,3 @@
+//! Provides output to the serial port.
+
+pub use util::serial::{SerialWriter, write_bytes};
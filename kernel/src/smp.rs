@@ -0,0 +1,337 @@
+//! Boots application processors.
+//!
+//! An application processor starts in real mode at a page below 1 MiB, so `ap_trampoline` is
+//! copied to [`LOW_PAGES`] with page tables mapping the page identically. It switches to long
+//! mode, and jumps to `ap_start` in the kernel.
+
+use alloc::{format, vec::Vec};
+use core::{
+    arch::global_asm,
+    iter, ptr,
+    sync::atomic::{self, AtomicBool, Ordering::*},
+};
+
+use log::{info, warn};
+use util::{
+    acpi::apic::InterruptController,
+    apic::{self, DeliveryMode},
+    asmfunc, error,
+    error::Result,
+    paging::{PAGE_SIZE, PageEntry, PageTable},
+    sync::OnceStatic,
+};
+
+use crate::{
+    acpi::MADT,
+    cpu::{self, MAX_CPUS},
+    fpu, gdt, interrupt,
+    memmap::{LOW_PAGES, LOW_PAGES_COUNT},
+    paging::{self, KERNEL_PML4},
+    task::TASK_MANAGER,
+    timer, tls,
+};
+
+/// Size of the kernel stack of each application processor in pages.
+const AP_STACK_PAGES: usize = 64;
+
+/// How long to wait for an application processor to start in milliseconds.
+const AP_START_TIMEOUT_MSEC: u32 = 100;
+
+/// MSR holding long mode settings.
+const IA32_EFER: u32 = 0xc000_0080;
+
+/// Local APIC IDs of enabled processors. The bootstrap processor comes first, and the index of each
+/// is its [`PerCpu::id()`](crate::cpu::PerCpu::id).
+pub static CPUS: OnceStatic<Vec<u8>> = OnceStatic::new();
+
+/// Set by an application processor when it no longer uses `ap_trampoline`.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Parameters read by `ap_trampoline`, placed at `ap_trampoline_params`.
+#[repr(C)]
+#[derive(Debug)]
+struct TrampolineParams {
+    /// Physical address of PML4 used until jumping to the kernel. Must be below 4 GiB.
+    pml4: u64,
+    cr3: u64,
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    /// The end of the stack of the processor.
+    stack: u64,
+    /// Address of `ap_start`.
+    entry: u64,
+    /// Passed to [`_ap_main()`].
+    apic_id: u64,
+}
+
+/// Boots all application processors enabled in MADT. Call this on the bootstrap processor after
+/// the timer is initialized.
+pub fn init() -> Result<()> {
+    let bsp = apic::local_apic_id();
+    let aps = MADT
+        .controllers()
+        .filter_map(|controller| match controller {
+            InterruptController::LocalApic(apic) if apic.enable() && apic.apic_id != bsp => {
+                Some(apic.apic_id)
+            }
+            _ => None,
+        });
+    let mut cpus: Vec<_> = iter::once(bsp).chain(aps).collect();
+    if cpus.len() > MAX_CPUS {
+        warn!("only {} of {} processors are used", MAX_CPUS, cpus.len());
+        cpus.truncate(MAX_CPUS);
+    }
+    CPUS.init(cpus);
+
+    if !LOW_PAGES.is_initialized() {
+        warn!("no page below 1 MiB is reserved, application processors are not booted");
+        return Ok(());
+    }
+    // Safety: the low pages are reserved for this and no processor uses them yet.
+    let params = unsafe { setup_trampoline(LOW_PAGES.get()) }?;
+
+    for &apic_id in &CPUS[1..] {
+        // The trampoline may still be used by the processor failed to start, so stop booting.
+        boot(apic_id, params)?;
+    }
+    info!("{} processors are running", CPUS.len());
+    Ok(())
+}
+
+/// Copies `ap_trampoline` and page tables for it to the pages starting at physical address `low`,
+/// and returns parameters in the copy to fill.
+///
+/// # Safety
+///
+/// [`LOW_PAGES_COUNT`] pages starting at `low` must not be used by others.
+unsafe fn setup_trampoline(low: u64) -> Result<&'static mut TrampolineParams> {
+    let code_start = ap_trampoline as *const () as usize;
+    let code_len = ap_trampoline_end as *const () as usize - code_start;
+    let params_offset = ap_trampoline_params as *const () as usize - code_start;
+    assert!(code_len <= PAGE_SIZE);
+
+    let Some(virt) = paging::pyhs_to_virt(low) else {
+        error!(format!("{:#x} is out of the straight mapping", low));
+    };
+    let page = |i: usize| virt.addr + (i * PAGE_SIZE) as u64;
+    let phys = |i: usize| low + (i * PAGE_SIZE) as u64;
+
+    // Safety: the caller guarantees the pages are not used, and they are in the straight mapping.
+    unsafe {
+        ptr::copy_nonoverlapping(code_start as *const u8, page(0) as *mut u8, code_len);
+
+        // The trampoline runs at the physical address, so map the first 2 MiB identically.
+        let [pml4, pdpt, pd] = [1, 2, 3].map(|i| &mut *(page(i) as *mut PageTable));
+        *pd = PageTable::new();
+        pd[0] = PageEntry::new(0, true, false);
+        pd[0].set_page_size(true);
+        *pdpt = PageTable::new();
+        pdpt[0] = PageEntry::new(phys(3), true, false);
+        *pml4 = PageTable::new();
+        pml4[0] = PageEntry::new(phys(2), true, false);
+        // The kernel half is required to jump to `ap_start`.
+        let kernel_pml4 = KERNEL_PML4.lock();
+        for i in 256..512 {
+            pml4[i] = kernel_pml4[i];
+        }
+    }
+    const _: () = assert!(LOW_PAGES_COUNT >= 4);
+
+    // Safety: `ap_trampoline_params` is 8-byte aligned and has room for `TrampolineParams`.
+    let params = unsafe { &mut *((page(0) as usize + params_offset) as *mut TrampolineParams) };
+    *params = TrampolineParams {
+        pml4: phys(1),
+        cr3: asmfunc::get_cr3(),
+        cr0: asmfunc::get_cr0(),
+        cr4: asmfunc::get_cr4(),
+        // Clear LMA, which is set by the processor.
+        efer: asmfunc::rdmsr(IA32_EFER) & !(1 << 10),
+        stack: 0,
+        entry: ap_start as *const () as u64,
+        apic_id: 0,
+    };
+    Ok(params)
+}
+
+/// Boots the application processor whose Local APIC ID is `apic_id` with INIT-SIPI-SIPI, and
+/// waits for it to start.
+fn boot(apic_id: u8, params: &mut TrampolineParams) -> Result<()> {
+    let stack = paging::reserve_stack(AP_STACK_PAGES, "AP kernel stack", None)?;
+    params.stack = stack.end;
+    params.apic_id = apic_id as u64;
+    AP_STARTED.store(false, Relaxed);
+    // Make the parameters visible before the processor starts.
+    atomic::fence(SeqCst);
+
+    apic::send_ipi(apic_id, DeliveryMode::Init, 0);
+    timer::wait_for_msec(10);
+    let vector = (LOW_PAGES.get() / PAGE_SIZE as u64) as u8;
+    // The second SIPI is ignored if the first one has started the processor.
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, DeliveryMode::StartUp, vector);
+        timer::wait_for_msec(1);
+    }
+
+    for _ in 0..AP_START_TIMEOUT_MSEC {
+        if AP_STARTED.load(Acquire) {
+            return Ok(());
+        }
+        timer::wait_for_msec(1);
+    }
+    error!(format!("CPU {} did not start", apic_id));
+}
+
+/// Entry of application processors called by `ap_start` with the stack for the processor.
+#[unsafe(no_mangle)]
+extern "sysv64" fn _ap_main(apic_id: u64) -> ! {
+    let result = init_ap(apic_id as u8);
+    // The bootstrap processor can boot the next one because this no longer uses the trampoline.
+    AP_STARTED.store(true, Release);
+    if let Err(e) = result {
+        log::error!("failed to initialize CPU {}: {}", apic_id, e);
+        loop {
+            asmfunc::cli();
+            asmfunc::hlt();
+        }
+    }
+    info!("CPU {} started", apic_id);
+
+    TASK_MANAGER.start();
+}
+
+/// Sets up the current application processor in the same way as the bootstrap processor.
+fn init_ap(apic_id: u8) -> Result<()> {
+    gdt::init()?;
+    let Some(id) = CPUS.iter().position(|&cpu| cpu == apic_id) else {
+        error!(format!("CPU {} is not in MADT", apic_id));
+    };
+    cpu::init(id, apic_id);
+    fpu::init();
+    tls::init_ap();
+    interrupt::init_ap();
+    apic::enable(interrupt::SPURIOUS_INT_VEC);
+    timer::init_ap();
+    TASK_MANAGER.init();
+    // Last, since processors failing to initialize never handle shootdowns.
+    paging::init_tlb_shootdown();
+    Ok(())
+}
+
+unsafe extern "sysv64" {
+    /// Real mode entry of application processors. This is copied to a page below 1 MiB, so refer
+    /// only to itself with relative addresses.
+    fn ap_trampoline();
+    /// Parameters of `ap_trampoline` laid out as [`TrampolineParams`].
+    fn ap_trampoline_params();
+    fn ap_trampoline_end();
+    /// Switches to the kernel page table and the stack, then calls [`_ap_main()`].
+    fn ap_start();
+}
+
+global_asm! { r#"
+.pushsection .text.ap_trampoline, "ax"
+# Offsets from `ap_trampoline`, used in real mode and protected mode.
+.set AP_GDT, ap_gdt - ap_trampoline
+.set AP_GDTR, ap_gdtr - ap_trampoline
+.set AP_FAR32, ap_far32 - ap_trampoline
+.set AP_FAR64, ap_far64 - ap_trampoline
+.set AP_PROTECTED, ap_protected - ap_trampoline
+.set AP_LONG, ap_long - ap_trampoline
+.set AP_PARAMS, ap_trampoline_params - ap_trampoline
+
+.code16
+.global ap_trampoline
+ap_trampoline:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    # EBX holds the physical address of the trampoline until long mode.
+    movzx ebx, ax
+    shl ebx, 4
+
+    lea eax, [ebx + AP_GDT]
+    mov dword ptr [AP_GDTR + 2], eax
+    lea eax, [ebx + AP_PROTECTED]
+    mov dword ptr [AP_FAR32], eax
+    lea eax, [ebx + AP_LONG]
+    mov dword ptr [AP_FAR64], eax
+
+    lgdt [AP_GDTR]
+    mov eax, cr0
+    or eax, 1                   # PE
+    mov cr0, eax
+    jmp fword ptr [AP_FAR32]
+
+.code32
+ap_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov eax, cr4
+    or eax, 1 << 5              # PAE
+    mov cr4, eax
+    mov eax, [ebx + AP_PARAMS]        # PML4
+    mov cr3, eax
+    mov ecx, 0xc0000080         # IA32_EFER
+    mov eax, [ebx + AP_PARAMS + 0x20] # EFER
+    xor edx, edx
+    wrmsr
+    mov eax, cr0
+    or eax, 1 << 31             # PG
+    mov cr0, eax
+    jmp fword ptr [ebx + AP_FAR64]
+
+.code64
+ap_long:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov rax, [rip + ap_trampoline_params + 0x10] # CR0
+    mov cr0, rax
+    mov rax, [rip + ap_trampoline_params + 0x18] # CR4
+    mov cr4, rax
+    mov rsi, [rip + ap_trampoline_params + 0x08] # CR3
+    mov rdx, [rip + ap_trampoline_params + 0x28] # stack
+    mov rdi, [rip + ap_trampoline_params + 0x38] # APIC ID
+    mov rax, [rip + ap_trampoline_params + 0x30] # ap_start
+    jmp rax
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff    # 32-bit code
+    .quad 0x00cf92000000ffff    # data
+    .quad 0x00af9a000000ffff    # 64-bit code
+ap_gdtr:
+    .word 4 * 8 - 1
+    .long 0
+ap_far32:
+    .long 0
+    .word 0x08
+ap_far64:
+    .long 0
+    .word 0x18
+
+.balign 8
+.global ap_trampoline_params
+ap_trampoline_params:
+    .fill 8, 8, 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+
+.global ap_start
+ap_start:
+    mov cr3, rsi
+    mov rsp, rdx
+    # Terminate the chain of frame pointers for backtraces.
+    xor ebp, ebp
+    call _ap_main
+    ud2
+"# }
@@ -0,0 +1,145 @@
+//! Runs deferred halves of interrupt handlers.
+//!
+//! An interrupt handler does minimal work with interrupts disabled, and raises the softirq of its
+//! vector by [`raise()`] to do the rest. The softirq runs on the same processor after the handler
+//! returns, with interrupts enabled and preemption disabled.
+//!
+//! Softirqs are run by the handler of [`SOFTIRQ_INT_VEC`], which [`raise()`] sends to the
+//! processor itself. So handlers running on IST stacks, which must never enable interrupts, can
+//! raise them too. A softirq raised while softirqs are running on the processor runs in the same
+//! batch.
+
+use alloc::{format, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+
+use util::{
+    apic::{self, DeliveryMode},
+    asmfunc, error,
+    error::Result,
+    sync::InterruptFreeMutex,
+};
+
+use crate::{
+    cpu::{self, this_cpu},
+    interrupt::SOFTIRQ_INT_VEC,
+};
+
+/// The number of vectors, each of which can have a softirq.
+const VECTOR_COUNT: usize = 256;
+
+/// Handler of a softirq.
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+/// Softirq handlers indexed by vector.
+static HANDLERS: InterruptFreeMutex<[Option<Handler>; VECTOR_COUNT]> =
+    InterruptFreeMutex::new([const { None }; VECTOR_COUNT]);
+
+/// Softirq state owned by each processor.
+#[derive(Debug)]
+pub struct SoftirqState {
+    /// Bitmap of vectors whose softirqs are raised and not run yet.
+    pending: [AtomicU64; VECTOR_COUNT / 64],
+    /// Whether the processor is running softirqs.
+    running: AtomicBool,
+}
+
+impl SoftirqState {
+    /// Constructs a state with no softirqs raised.
+    pub const fn new() -> Self {
+        Self {
+            pending: [const { AtomicU64::new(0) }; VECTOR_COUNT / 64],
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether any softirq is raised.
+    fn is_pending(&self) -> bool {
+        self.pending.iter().any(|word| word.load(Relaxed) != 0)
+    }
+}
+
+impl Default for SoftirqState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registers `handler` as the softirq of `vector`. Each vector can have only one softirq.
+///
+/// Handlers run with interrupts enabled but must not sleep, because preemption is disabled.
+pub fn register(vector: u8, handler: impl Fn() + Send + Sync + 'static) -> Result<()> {
+    let mut handlers = HANDLERS.lock();
+    let slot = &mut handlers[vector as usize];
+    if slot.is_some() {
+        error!(format!("vector {:#x} already has a softirq", vector));
+    }
+    *slot = Some(Arc::new(handler));
+    Ok(())
+}
+
+/// Unregisters the softirq of `vector`. Raised ones that have not run are ignored.
+pub fn unregister(vector: u8) -> Result<()> {
+    let Some(handler) = HANDLERS.lock()[vector as usize].take() else {
+        error!(format!("vector {:#x} has no softirq", vector));
+    };
+    // The handler may capture something slow to drop, so drop it after unlocking.
+    drop(handler);
+    Ok(())
+}
+
+/// Raises the softirq of `vector` on the current processor. Can be called from interrupt
+/// handlers. Raising the same one again before it runs has no effect.
+pub fn raise(vector: u8) {
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    let cpu = this_cpu();
+    let state = cpu.softirq();
+    // Otherwise an IPI is on the way, or the running batch picks it up.
+    let kick = !state.is_pending() && !state.running.load(Relaxed);
+    state.pending[vector as usize / 64].fetch_or(1 << (vector % 64), Relaxed);
+    if kick {
+        apic::send_ipi(cpu.apic_id(), DeliveryMode::Fixed, SOFTIRQ_INT_VEC);
+    }
+    if if_is_set {
+        asmfunc::sti();
+    }
+}
+
+/// Runs raised softirqs of the current processor. Called by `int_handler_softirq` with interrupts
+/// disabled.
+#[unsafe(no_mangle)]
+extern "sysv64" fn _int_handler_softirq() {
+    apic::notify_end_of_interrupt();
+    let state = this_cpu().softirq();
+    if state.running.swap(true, Relaxed) {
+        // An interrupt has come while running softirqs.
+        return;
+    }
+
+    // Keep the task on the processor so that `state` stays the current processor's.
+    cpu::preempt_disable();
+    loop {
+        let pending = state.pending.each_ref().map(|word| word.swap(0, Relaxed));
+        if pending.iter().all(|&word| word == 0) {
+            // Interrupts are disabled, so no softirq is raised between the check and this.
+            state.running.store(false, Relaxed);
+            break;
+        }
+
+        asmfunc::sti();
+        for (i, mut word) in pending.into_iter().enumerate() {
+            while word != 0 {
+                let vector = i * 64 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                let handler = HANDLERS.lock()[vector].clone();
+                match handler {
+                    Some(handler) => handler(),
+                    None => log::warn!("no softirq for vector {:#x}", vector),
+                }
+            }
+        }
+        asmfunc::cli();
+    }
+    // Switches tasks deferred while running softirqs on returning from the interrupt.
+    cpu::preempt_enable();
+}
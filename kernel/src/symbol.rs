@@ -0,0 +1,171 @@
+//! Symbolizes kernel addresses with the symbol table passed by the loader.
+//!
+//! Only function names are available. Source file names and line numbers require DWARF line
+//! tables, which are stripped from the kernel image.
+
+use core::{
+    fmt::{self, Display},
+    slice,
+};
+
+use util::{
+    elf::{Elf64Sym, SymbolTable, SymbolTableInfo},
+    sync::OnceStatic,
+};
+
+use crate::paging;
+
+/// Symbol table of the kernel.
+static SYMBOLS: OnceStatic<SymbolTable<'static>> = OnceStatic::new();
+
+/// Initializes the symbol table with `info` passed by the loader. Call this after the straight
+/// mapping is initialized.
+pub fn init(info: &SymbolTableInfo) {
+    if info.symtab == 0 {
+        log::warn!("no symbol table is passed, backtraces will not be symbolized");
+        return;
+    }
+    let (Some(symtab), Some(strtab)) = (
+        paging::pyhs_to_virt(info.symtab),
+        paging::pyhs_to_virt(info.strtab),
+    ) else {
+        log::warn!("symbol table is out of the straight mapping");
+        return;
+    };
+
+    // Safety: the loader copied the tables to pages the kernel never frees.
+    let (symbols, strtab) = unsafe {
+        (
+            slice::from_raw_parts(
+                symtab.addr as *const Elf64Sym,
+                info.symtab_size as usize / size_of::<Elf64Sym>(),
+            ),
+            slice::from_raw_parts(strtab.addr as *const u8, info.strtab_size as _),
+        )
+    };
+    SYMBOLS.init(SymbolTable::new(symbols, strtab));
+}
+
+/// Returns the name of the function containing `addr` and the offset of `addr` from its start.
+/// The name is mangled. Use [`Demangle`] to display it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    if !SYMBOLS.is_initialized() {
+        return None;
+    }
+    SYMBOLS.lookup(addr)
+}
+
+/// Displays a symbol name demangled in the Rust legacy mangling scheme, like
+/// `kernel::task::TaskManager::switch` from `_ZN6kernel4task11TaskManager6switch17h...E`. Names
+/// not mangled in the scheme are displayed as is.
+#[derive(Debug, Clone, Copy)]
+pub struct Demangle<'a>(pub &'a str);
+
+impl Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(path) = self
+            .0
+            .strip_prefix("_ZN")
+            .and_then(|path| path.strip_suffix('E'))
+            .filter(|path| Segments(path).all(|segment| segment.is_some()))
+        else {
+            return f.write_str(self.0);
+        };
+
+        let mut segments = Segments(path).flatten().peekable();
+        let mut first = true;
+        while let Some(segment) = segments.next() {
+            // Skip the hash at the end.
+            if segments.peek().is_none() && is_hash(segment) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterates length-prefixed segments of a mangled path. Yields `None` if the path is malformed.
+struct Segments<'a>(&'a str);
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Option<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let segment = self.0[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| self.0.get(digits..digits + len));
+        match segment {
+            Some(segment) => {
+                self.0 = &self.0[digits + segment.len()..];
+                Some(Some(segment))
+            }
+            None => {
+                self.0 = "";
+                Some(None)
+            }
+        }
+    }
+}
+
+/// Returns whether `segment` is a hash like `h0123456789abcdef`.
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17
+        && segment.starts_with('h')
+        && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Writes `segment` replacing escapes like `$LT$` and `..`.
+fn write_segment(f: &mut fmt::Formatter<'_>, segment: &str) -> fmt::Result {
+    // A leading underscore is inserted before an escape.
+    let mut rest = if segment.starts_with("_$") {
+        &segment[1..]
+    } else {
+        segment
+    };
+    while !rest.is_empty() {
+        if let Some(escaped) = rest.strip_prefix('$')
+            && let Some(end) = escaped.find('$')
+            && let Some(c) = unescape(&escaped[..end])
+        {
+            write!(f, "{}", c)?;
+            rest = &escaped[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else {
+            let len = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '$' || c == '.')
+                .map_or(rest.len(), |(i, _)| i);
+            f.write_str(&rest[..len])?;
+            rest = &rest[len..];
+        }
+    }
+    Ok(())
+}
+
+/// Returns the character represented by an escape `code` between `$`s.
+fn unescape(code: &str) -> Option<char> {
+    Some(match code {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => char::from_u32(u32::from_str_radix(code.strip_prefix('u')?, 16).ok()?)?,
+    })
+}
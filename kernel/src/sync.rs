@@ -3,14 +3,29 @@
 use core::{
     cell::UnsafeCell,
     fmt::{Debug, Display},
+    future::Future,
     hint,
     ops::{Deref, DerefMut},
+    pin::Pin,
     sync::atomic::{AtomicBool, Ordering::*},
+    task::{Context, Poll, Waker},
 };
 
 use alloc::collections::VecDeque;
+use util::{asmfunc, sync::InterruptFreeMutex};
 
-use crate::task::{TASK_MANAGER, TaskId};
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+#[cfg(feature = "lockdep")]
+use util::lockdep::{self, HeldLocks, LockClass};
+
+use crate::{
+    cpu,
+    task::{TASK_MANAGER, TaskId},
+};
+
+pub mod channel;
 
 /// Shared reference providing mutable exclusion.
 pub struct Mutex<T> {
@@ -22,6 +37,8 @@ pub struct Mutex<T> {
     queue: UnsafeCell<VecDeque<TaskId>>,
     /// Lock for controlling [`queue`](Mutex<T>.queue).
     queue_lock: AtomicBool,
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
 }
 
 // Safety: `Mutex` provides exclusive mutability from even its shared reference. That is, sending a
@@ -31,16 +48,20 @@ unsafe impl<T: Send> Sync for Mutex<T> {}
 
 impl<T> Mutex<T> {
     /// Constructs new [`Mutex<T>`] with initial value, `value`.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub const fn new(value: T) -> Self {
         Self {
             data: UnsafeCell::new(value),
             lock: AtomicBool::new(false),
             queue: UnsafeCell::new(VecDeque::new()),
             queue_lock: AtomicBool::new(false),
+            #[cfg(feature = "lockdep")]
+            class: LockClass::new(),
         }
     }
 
     /// Trys to lock and returns [`MutexGuard`] if succeeded.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
         // Check whether there are tasks waiting for the lock to be released. If there are, we
         // won't try to lock to yield the lock.
@@ -65,17 +86,21 @@ impl<T> Mutex<T> {
         {
             None
         } else {
+            #[cfg(feature = "lockdep")]
+            self.validate(true);
             Some(MutexGuard {
                 data: unsafe { &mut *self.data.get() },
-                lock: &self.lock,
-                queue: &self.queue,
-                queue_lock: &self.queue_lock,
+                mutex: self,
+                _held: SleepingLockHeld::new(),
             })
         }
     }
 
     /// Acquires the lock and returns the handle to control the inner data.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        self.validate(false);
         let task_id = TASK_MANAGER.task_id();
         let mut waiting = false;
 
@@ -149,9 +174,8 @@ impl<T> Mutex<T> {
 
         MutexGuard {
             data: unsafe { &mut *self.data.get() },
-            lock: &self.lock,
-            queue: &self.queue,
-            queue_lock: &self.queue_lock,
+            mutex: self,
+            _held: SleepingLockHeld::new(),
         }
     }
 
@@ -165,6 +189,18 @@ impl<T> Mutex<T> {
     pub fn get_mut(&mut self) -> &mut T {
         unsafe { &mut *self.data.get() }
     }
+
+    /// Records taking the lock in the held locks of the current task, ordered after the locks
+    /// held by the task and the processor.
+    #[cfg(feature = "lockdep")]
+    #[track_caller]
+    fn validate(&self, trylock: bool) {
+        let addr = self as *const Self as usize;
+        let site = Location::caller();
+        with_task_locks(|held| {
+            lockdep::acquire(held, lockdep::cpu_locks(), &self.class, addr, site, trylock);
+        });
+    }
 }
 
 impl<T: Debug> Debug for Mutex<T> {
@@ -179,28 +215,81 @@ impl<T: Debug> Debug for Mutex<T> {
     }
 }
 
+/// Calls `f` with the held locks of the current task, if tasks have started.
+#[cfg(feature = "lockdep")]
+fn with_task_locks(f: impl FnOnce(&HeldLocks)) {
+    if cpu::try_this_cpu().is_none() {
+        return;
+    }
+    // Otherwise the task may move to another processor, which runs another task, after looking
+    // up the processor.
+    let _preempt = cpu::PreemptGuard::new();
+    if let Some(held) = cpu::this_cpu().task_locks() {
+        f(held);
+    }
+}
+
+/// Counts a guard of a sleeping lock as held by the current task while it lives, so that the task
+/// is not killed holding it. See [`TaskManager::holds_sleeping_locks()`].
+///
+/// [`TaskManager::holds_sleeping_locks()`]: crate::task::TaskManager::holds_sleeping_locks
+#[derive(Debug)]
+struct SleepingLockHeld(());
+
+impl SleepingLockHeld {
+    fn new() -> Self {
+        update_sleeping_locks(|count| count.checked_add(1));
+        Self(())
+    }
+}
+
+impl Drop for SleepingLockHeld {
+    fn drop(&mut self) {
+        // Guards taken before tasks start or moved to another task are not counted.
+        update_sleeping_locks(|count| count.checked_sub(1));
+    }
+}
+
+/// Updates the number of sleeping locks held by the current task with `f`, if tasks have started.
+fn update_sleeping_locks(f: impl FnMut(u32) -> Option<u32>) {
+    if cpu::try_this_cpu().is_none() {
+        return;
+    }
+    // Keep the task on the processor until the count is updated.
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    if let Some(count) = cpu::this_cpu().task_sleeping_locks() {
+        let _ = count.fetch_update(Relaxed, Relaxed, f);
+    }
+    if if_is_set {
+        asmfunc::sti();
+    }
+}
+
 /// Provides exclusive control to the inner value of [`Mutex<T>`]. Releases the lock when dropped.
 pub struct MutexGuard<'this, T> {
     data: &'this mut T,
-    lock: &'this AtomicBool,
-    queue: &'this UnsafeCell<VecDeque<TaskId>>,
-    queue_lock: &'this AtomicBool,
+    mutex: &'this Mutex<T>,
+    _held: SleepingLockHeld,
 }
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
-        self.lock.store(false, Release);
+        #[cfg(feature = "lockdep")]
+        with_task_locks(|held| lockdep::release(held, self.mutex as *const _ as usize));
+        self.mutex.lock.store(false, Release);
         while self
+            .mutex
             .queue_lock
             .compare_exchange_weak(false, true, Acquire, Relaxed)
             .is_err()
         {
             hint::spin_loop();
         }
-        if let Some(&next_id) = unsafe { (*self.queue.get()).front() } {
+        if let Some(&next_id) = unsafe { (*self.mutex.queue.get()).front() } {
             TASK_MANAGER.wake_up(next_id);
         }
-        self.queue_lock.store(false, Release);
+        self.mutex.queue_lock.store(false, Release);
     }
 }
 
@@ -229,3 +318,743 @@ impl<T: Display> Display for MutexGuard<'_, T> {
         Display::fmt(self.data, f)
     }
 }
+
+/// Mutex for futures. Waiting for the lock suspends the future instead of the task, so futures on
+/// the same [`Executor`](crate::executor::Executor) keep running.
+///
+/// ```ignore
+/// let mut port = PORT.lock().await;
+/// port.issue(command).await;
+/// ```
+pub struct AsyncMutex<T> {
+    data: UnsafeCell<T>,
+    state: InterruptFreeMutex<AsyncMutexState>,
+}
+
+/// Lock state of [`AsyncMutex`].
+#[derive(Debug)]
+struct AsyncMutexState {
+    locked: bool,
+    /// Waiter which the last holder has handed the lock over to, keeping `locked` set.
+    handed_to: Option<u64>,
+    /// Futures waiting for the lock in arrival order, identified by numbers.
+    waiters: VecDeque<(u64, Waker)>,
+    /// Number that will be assigned to the next waiter.
+    next_id: u64,
+}
+
+// Safety: Same as `Mutex<T>`.
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    /// Constructs new [`AsyncMutex<T>`] with initial value, `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            data: UnsafeCell::new(value),
+            state: InterruptFreeMutex::new(AsyncMutexState {
+                locked: false,
+                handed_to: None,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Tries to lock without waiting, and returns [`AsyncMutexGuard`] if succeeded. Fails while
+    /// any future waits for the lock.
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(AsyncMutexGuard {
+            data: unsafe { &mut *self.data.get() },
+            mutex: self,
+        })
+    }
+
+    /// Returns a future that acquires the lock and resolves to the handle to control the inner
+    /// data. Waiters acquire the lock in arrival order, because releasing it hands it over to the
+    /// first waiter.
+    pub fn lock(&self) -> AsyncMutexLock<'_, T> {
+        AsyncMutexLock {
+            mutex: self,
+            id: None,
+        }
+    }
+
+    /// Consumes `self` and returns the inner value.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Returns an exclusive reference to the inner value without locking, since the exclusive
+    /// reference to `self` already ensures that no one else accesses it.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Hands the lock over to the first waiter and wakes it up, or unlocks if there is none.
+    fn unlock(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            match state.waiters.pop_front() {
+                Some((id, waker)) => {
+                    state.handed_to = Some(id);
+                    Some(waker)
+                }
+                None => {
+                    state.locked = false;
+                    None
+                }
+            }
+        };
+        // Wake it after unlocking because it may lock again.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: Debug> Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut f = f.debug_struct("AsyncMutex");
+        if let Some(guard) = self.try_lock() {
+            f.field("data", &*guard);
+        } else {
+            f.field("data", &format_args!("<locked>"));
+        }
+        f.finish_non_exhaustive()
+    }
+}
+
+/// Future returned by [`AsyncMutex::lock()`].
+#[must_use = "futures do nothing unless polled"]
+pub struct AsyncMutexLock<'this, T> {
+    mutex: &'this AsyncMutex<T>,
+    /// Number of the waiter once it waits.
+    id: Option<u64>,
+}
+
+impl<'this, T> Future for AsyncMutexLock<'this, T> {
+    type Output = AsyncMutexGuard<'this, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        let acquired = match self.id {
+            Some(id) => state.handed_to == Some(id),
+            None => !state.locked,
+        };
+        if acquired {
+            state.locked = true;
+            state.handed_to = None;
+            self.id = None;
+            return Poll::Ready(AsyncMutexGuard {
+                data: unsafe { &mut *mutex.data.get() },
+                mutex,
+            });
+        }
+
+        match self.id {
+            // Not handed over yet, so it is still waiting.
+            Some(id) => {
+                if let Some((_, waker)) = state.waiters.iter_mut().find(|(i, _)| *i == id) {
+                    waker.clone_from(cx.waker());
+                }
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                self.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for AsyncMutexLock<'_, T> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut state = self.mutex.state.lock();
+        if state.handed_to == Some(id) {
+            // Handed the lock over but dropped before taking it, so pass it on to the next one.
+            state.handed_to = None;
+            drop(state);
+            self.mutex.unlock();
+        } else if let Some(position) = state.waiters.iter().position(|&(i, _)| i == id) {
+            state.waiters.remove(position);
+        }
+    }
+}
+
+impl<T> Debug for AsyncMutexLock<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AsyncMutexLock")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Provides exclusive control to the inner value of [`AsyncMutex<T>`]. Releases the lock when
+/// dropped.
+pub struct AsyncMutexGuard<'this, T> {
+    data: &'this mut T,
+    mutex: &'this AsyncMutex<T>,
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+impl<T: Debug> Debug for AsyncMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&self.data, f)
+    }
+}
+
+impl<T: Display> Display for AsyncMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self.data, f)
+    }
+}
+
+/// Tasks sleeping until the state they wait on changes. The state is guarded by an
+/// [`InterruptFreeMutex`], so it can be changed from interrupt handlers.
+#[derive(Debug, Default)]
+pub(crate) struct WaitQueue(VecDeque<TaskId>);
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        Self(VecDeque::new())
+    }
+
+    /// Appends `id` unless it is already waiting.
+    fn push(&mut self, id: TaskId) {
+        if !self.0.contains(&id) {
+            self.0.push_back(id);
+        }
+    }
+
+    fn remove(&mut self, id: TaskId) {
+        self.0.retain(|&waiter| waiter != id);
+    }
+
+    /// Wakes up the task waiting longest, if any.
+    pub(crate) fn wake_one(&mut self) {
+        if let Some(id) = self.0.pop_front() {
+            TASK_MANAGER.wake_up(id);
+        }
+    }
+
+    pub(crate) fn wake_all(&mut self) {
+        for id in self.0.drain(..) {
+            TASK_MANAGER.wake_up(id);
+        }
+    }
+}
+
+/// Sleeps the current task in the wait queue returned by `queue` until `acquire` returns `Some`,
+/// and returns its value. `acquire` is called with `state` locked every time the task wakes up.
+pub(crate) fn wait_until<S, R>(
+    state: &InterruptFreeMutex<S>,
+    queue: impl Fn(&mut S) -> &mut WaitQueue,
+    mut acquire: impl FnMut(&mut S) -> Option<R>,
+) -> R {
+    debug_assert!(!TASK_MANAGER.is_idle());
+    let id = TASK_MANAGER.task_id();
+    loop {
+        {
+            let mut state = state.lock();
+            if let Some(value) = acquire(&mut state) {
+                // Woken up by others than the queue, if still listed.
+                queue(&mut state).remove(id);
+                return value;
+            }
+            queue(&mut state).push(id);
+        }
+        // A wake-up between unlocking and sleeping makes `sleep()` return immediately.
+        TASK_MANAGER.sleep();
+    }
+}
+
+/// Reader-writer lock which puts waiting tasks to sleep.
+///
+/// Writers are preferred: once a writer waits, new readers wait until no writer waits, so readers
+/// cannot starve writers.
+pub struct RwLock<T> {
+    data: UnsafeCell<T>,
+    state: InterruptFreeMutex<RwLockState>,
+}
+
+#[derive(Debug)]
+struct RwLockState {
+    /// The number of readers holding the lock.
+    readers: usize,
+    /// Whether a writer holds the lock.
+    writer: bool,
+    /// The number of writers waiting for the lock.
+    waiting_writers: usize,
+    reader_queue: WaitQueue,
+    writer_queue: WaitQueue,
+}
+
+impl RwLockState {
+    fn try_read(&mut self) -> bool {
+        let ok = !self.writer && self.waiting_writers == 0;
+        if ok {
+            self.readers += 1;
+        }
+        ok
+    }
+
+    fn try_write(&mut self) -> bool {
+        let ok = !self.writer && self.readers == 0;
+        if ok {
+            self.writer = true;
+        }
+        ok
+    }
+}
+
+// Safety: Readers share `&T` across tasks, so `T` must also be `Sync`.
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Constructs new [`RwLock<T>`] with initial value, `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            data: UnsafeCell::new(value),
+            state: InterruptFreeMutex::new(RwLockState {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+                reader_queue: WaitQueue::new(),
+                writer_queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Tries to acquire shared access without sleeping, and returns [`RwLockReadGuard`] if
+    /// succeeded. Fails also while a writer is waiting.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state.lock().try_read().then(|| RwLockReadGuard {
+            data: unsafe { &*self.data.get() },
+            lock: self,
+            _held: SleepingLockHeld::new(),
+        })
+    }
+
+    /// Tries to acquire exclusive access without sleeping, and returns [`RwLockWriteGuard`] if
+    /// succeeded.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.lock().try_write().then(|| RwLockWriteGuard {
+            data: unsafe { &mut *self.data.get() },
+            lock: self,
+            _held: SleepingLockHeld::new(),
+        })
+    }
+
+    /// Acquires shared access, sleeping while a writer holds or waits for the lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        wait_until(
+            &self.state,
+            |state| &mut state.reader_queue,
+            |state| state.try_read().then_some(()),
+        );
+        RwLockReadGuard {
+            data: unsafe { &*self.data.get() },
+            lock: self,
+            _held: SleepingLockHeld::new(),
+        }
+    }
+
+    /// Acquires exclusive access, sleeping while others hold the lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        {
+            let mut state = self.state.lock();
+            if state.try_write() {
+                drop(state);
+                return RwLockWriteGuard {
+                    data: unsafe { &mut *self.data.get() },
+                    lock: self,
+                    _held: SleepingLockHeld::new(),
+                };
+            }
+            // Keep new readers out from now on.
+            state.waiting_writers += 1;
+        }
+        wait_until(
+            &self.state,
+            |state| &mut state.writer_queue,
+            |state| {
+                let ok = state.try_write();
+                if ok {
+                    state.waiting_writers -= 1;
+                }
+                ok.then_some(())
+            },
+        );
+        RwLockWriteGuard {
+            data: unsafe { &mut *self.data.get() },
+            lock: self,
+            _held: SleepingLockHeld::new(),
+        }
+    }
+
+    /// Consumes `self` and returns the inner value.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Returns an exclusive reference to the inner value. No lock is taken, because `&mut self`
+    /// guarantees that no guard exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Debug> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut f = f.debug_struct("RwLock");
+        if let Some(guard) = self.try_read() {
+            f.field("data", &*guard);
+        } else {
+            f.field("data", &format_args!("<locked>"));
+        }
+        f.finish_non_exhaustive()
+    }
+}
+
+/// Provides shared access to the inner value of [`RwLock<T>`]. Releases the lock when dropped.
+pub struct RwLockReadGuard<'this, T> {
+    data: &'this T,
+    lock: &'this RwLock<T>,
+    _held: SleepingLockHeld,
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.writer_queue.wake_one();
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<T: Debug> Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(self.data, f)
+    }
+}
+
+impl<T: Display> Display for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self.data, f)
+    }
+}
+
+/// Provides exclusive access to the inner value of [`RwLock<T>`]. Releases the lock when dropped.
+pub struct RwLockWriteGuard<'this, T> {
+    data: &'this mut T,
+    lock: &'this RwLock<T>,
+    _held: SleepingLockHeld,
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        if state.waiting_writers != 0 {
+            state.writer_queue.wake_one();
+        } else {
+            state.reader_queue.wake_all();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+impl<T: Debug> Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&self.data, f)
+    }
+}
+
+impl<T: Display> Display for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self.data, f)
+    }
+}
+
+/// Counting semaphore which puts tasks waiting for a permit to sleep.
+#[derive(Debug)]
+pub struct Semaphore {
+    state: InterruptFreeMutex<SemaphoreState>,
+}
+
+#[derive(Debug)]
+struct SemaphoreState {
+    permits: usize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    /// Constructs a semaphore with `permits` permits available.
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: InterruptFreeMutex::new(SemaphoreState {
+                permits,
+                queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of permits available now.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Takes a permit if available. Can be called from interrupt handlers.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        let ok = state.permits != 0;
+        if ok {
+            state.permits -= 1;
+        }
+        ok
+    }
+
+    /// Takes a permit, sleeping until one is released. Unlike [`Semaphore::access()`], the permit
+    /// is not counted as held by the task, so the page fault handler may kill the task with it.
+    pub fn acquire(&self) {
+        wait_until(
+            &self.state,
+            |state| &mut state.queue,
+            |state| {
+                let ok = state.permits != 0;
+                if ok {
+                    state.permits -= 1;
+                }
+                ok.then_some(())
+            },
+        );
+    }
+
+    /// Takes a permit like [`Semaphore::acquire()`], and returns a guard releasing it when
+    /// dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        self.acquire();
+        SemaphoreGuard {
+            semaphore: self,
+            _held: SleepingLockHeld::new(),
+        }
+    }
+
+    /// Adds a permit and wakes up a waiting task. Can be called from interrupt handlers.
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        state.permits += 1;
+        state.queue.wake_one();
+    }
+}
+
+/// Holds a permit of [`Semaphore`]. Releases it when dropped.
+#[derive(Debug)]
+pub struct SemaphoreGuard<'this> {
+    semaphore: &'this Semaphore,
+    _held: SleepingLockHeld,
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+/// Condition variable used with [`Mutex`]. Waiting tasks sleep until notified.
+///
+/// Waiting may return spuriously, because a task wakes up on any [`TaskManager::wake_up()`]. Check
+/// the condition in a loop, or use [`Condvar::wait_while()`].
+///
+/// [`TaskManager::wake_up()`]: crate::task::TaskManager::wake_up
+#[derive(Debug)]
+pub struct Condvar {
+    queue: InterruptFreeMutex<WaitQueue>,
+}
+
+impl Condvar {
+    /// Constructs a condition variable with no waiting tasks.
+    pub const fn new() -> Self {
+        Self {
+            queue: InterruptFreeMutex::new(WaitQueue::new()),
+        }
+    }
+
+    /// Releases the lock held by `guard`, sleeps until notified, and acquires the lock again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        debug_assert!(!TASK_MANAGER.is_idle());
+        let id = TASK_MANAGER.task_id();
+        let mutex = guard.mutex;
+        // Queue before unlocking so that a notification right after unlocking is not lost.
+        self.queue.lock().push(id);
+        drop(guard);
+        TASK_MANAGER.sleep();
+        // Not removed by the notifier if woken up spuriously.
+        self.queue.lock().remove(id);
+        mutex.lock()
+    }
+
+    /// Waits like [`Condvar::wait()`] while `condition` returns `true` for the inner value.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes up the task waiting longest. Can be called from interrupt handlers.
+    pub fn notify_one(&self) {
+        self.queue.lock().wake_one();
+    }
+
+    /// Wakes up all waiting tasks. Can be called from interrupt handlers.
+    pub fn notify_all(&self) {
+        self.queue.lock().wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Event which tasks wait to be set.
+///
+/// A one-shot event stays set once set, releasing all waiters until reset. An auto-reset event
+/// releases a single waiter per [`Event::set()`] and is reset by it. Setting an event already set
+/// has no effect.
+#[derive(Debug)]
+pub struct Event {
+    auto_reset: bool,
+    state: InterruptFreeMutex<EventState>,
+}
+
+#[derive(Debug)]
+struct EventState {
+    set: bool,
+    queue: WaitQueue,
+}
+
+impl Event {
+    /// Constructs an unset one-shot event.
+    pub const fn one_shot() -> Self {
+        Self::new(false)
+    }
+
+    /// Constructs an unset auto-reset event.
+    pub const fn auto_reset() -> Self {
+        Self::new(true)
+    }
+
+    const fn new(auto_reset: bool) -> Self {
+        Self {
+            auto_reset,
+            state: InterruptFreeMutex::new(EventState {
+                set: false,
+                queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Returns whether the event is set.
+    pub fn is_set(&self) -> bool {
+        self.state.lock().set
+    }
+
+    /// Sets the event and wakes up waiting tasks: all of them if one-shot, or one if auto-reset.
+    /// Can be called from interrupt handlers.
+    pub fn set(&self) {
+        let mut state = self.state.lock();
+        state.set = true;
+        if self.auto_reset {
+            state.queue.wake_one();
+        } else {
+            state.queue.wake_all();
+        }
+    }
+
+    /// Unsets the event.
+    pub fn reset(&self) {
+        self.state.lock().set = false;
+    }
+
+    /// Returns whether the event is set without sleeping. Resets an auto-reset event if set.
+    pub fn try_wait(&self) -> bool {
+        let mut state = self.state.lock();
+        self.consume(&mut state)
+    }
+
+    /// Sleeps until the event is set. Resets an auto-reset event.
+    pub fn wait(&self) {
+        wait_until(
+            &self.state,
+            |state| &mut state.queue,
+            |state| self.consume(state).then_some(()),
+        );
+    }
+
+    fn consume(&self, state: &mut EventState) -> bool {
+        let set = state.set;
+        if set && self.auto_reset {
+            state.set = false;
+        }
+        set
+    }
+}
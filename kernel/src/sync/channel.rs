@@ -0,0 +1,302 @@
+//! Passes values between tasks through queues.
+//!
+//! [`unbounded()`] creates a channel with any number of senders, and [`bounded()`] a channel with
+//! a single sender and a fixed capacity. Both have a single [`Receiver`], which sleeps until a
+//! value arrives. Sending never sleeps except [`BoundedSender::send()`], so interrupt handlers can
+//! feed tasks:
+//!
+//! ```ignore
+//! let (tx, rx) = channel::unbounded();
+//! interrupt::register_handler(vector, move |_| {
+//!     let _ = tx.send(read_scancode());
+//! })?;
+//! while let Ok(scancode) = rx.recv() {
+//!     console.handle(scancode);
+//! }
+//! ```
+//!
+//! The channel is closed once all senders or the receiver is dropped. The receiver still gets the
+//! values sent before that.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{fmt, mem};
+
+use util::sync::InterruptFreeMutex;
+
+use super::{WaitQueue, wait_until};
+
+/// Creates a channel without limit of queued values. The sender can be cloned.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel::new(usize::MAX));
+    (Sender(Arc::clone(&channel)), Receiver(channel))
+}
+
+/// Creates a channel queueing up to `capacity` values.
+///
+/// # Panics
+///
+/// Panics if `capacity` is `0`.
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, Receiver<T>) {
+    assert_ne!(capacity, 0, "channel capacity must not be 0");
+    let channel = Arc::new(Channel::new(capacity));
+    (BoundedSender(Arc::clone(&channel)), Receiver(channel))
+}
+
+/// State shared by the ends of a channel.
+struct Channel<T> {
+    capacity: usize,
+    state: InterruptFreeMutex<State<T>>,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    /// The receiver sleeping until a value is sent.
+    receiver: WaitQueue,
+    /// The bounded sender sleeping until the queue has room.
+    sender: WaitQueue,
+}
+
+impl<T> Channel<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: InterruptFreeMutex::new(State {
+                queue: VecDeque::new(),
+                senders: 1,
+                receiver_alive: true,
+                receiver: WaitQueue::new(),
+                sender: WaitQueue::new(),
+            }),
+        }
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.state.lock();
+        if !state.receiver_alive {
+            return Err(TrySendError::Disconnected(value));
+        }
+        if state.queue.len() >= self.capacity {
+            return Err(TrySendError::Full(value));
+        }
+        state.queue.push_back(value);
+        state.receiver.wake_one();
+        Ok(())
+    }
+
+    fn drop_sender(&self) {
+        let mut state = self.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.receiver.wake_one();
+        }
+    }
+}
+
+/// Sending end of a channel created by [`unbounded()`].
+pub struct Sender<T>(Arc<Channel<T>>);
+
+impl<T> Sender<T> {
+    /// Queues `value` to the receiver. Never sleeps, so this can be called from interrupt
+    /// handlers. Returns `value` back if the receiver has been dropped.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.0
+            .try_send(value)
+            .map_err(|err| SendError(err.into_inner()))
+    }
+
+    /// Returns whether the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.0.state.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.state.lock().senders += 1;
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.0.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// Sending end of a channel created by [`bounded()`].
+pub struct BoundedSender<T>(Arc<Channel<T>>);
+
+impl<T> BoundedSender<T> {
+    /// Queues `value` to the receiver, sleeping while the channel is full. Returns `value` back
+    /// if the receiver has been dropped.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        wait_until(
+            &self.0.state,
+            |state| &mut state.sender,
+            |state| {
+                if !state.receiver_alive {
+                    return Some(Err(SendError(value.take().unwrap())));
+                }
+                if state.queue.len() >= self.0.capacity {
+                    return None;
+                }
+                state.queue.push_back(value.take().unwrap());
+                state.receiver.wake_one();
+                Some(Ok(()))
+            },
+        )
+    }
+
+    /// Queues `value` to the receiver if the channel has room. Never sleeps, so this can be called
+    /// from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.0.try_send(value)
+    }
+
+    /// Returns whether the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.0.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        self.0.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for BoundedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoundedSender").finish_non_exhaustive()
+    }
+}
+
+/// Receiving end of a channel.
+pub struct Receiver<T>(Arc<Channel<T>>);
+
+impl<T> Receiver<T> {
+    /// Takes the oldest value, sleeping until one is sent. Fails once the channel is empty and all
+    /// senders have been dropped.
+    ///
+    /// Do not call this from idle tasks, which must never sleep.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        wait_until(
+            &self.0.state,
+            |state| &mut state.receiver,
+            |state| match Self::take(state) {
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+                Ok(value) => Some(Ok(value)),
+            },
+        )
+    }
+
+    /// Takes the oldest value without sleeping.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        Self::take(&mut self.0.state.lock())
+    }
+
+    fn take(state: &mut State<T>) -> Result<T, TryRecvError> {
+        match state.queue.pop_front() {
+            Some(value) => {
+                state.sender.wake_one();
+                Ok(value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let queue = {
+            let mut state = self.0.state.lock();
+            state.receiver_alive = false;
+            state.sender.wake_all();
+            mem::take(&mut state.queue)
+        };
+        // Values may be slow to drop, so drop them after unlocking.
+        drop(queue);
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// Error of sending to a channel whose receiver has been dropped. Holds the value not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a closed channel")
+    }
+}
+
+/// Error of sending to a channel without sleeping. Holds the value not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel has no room.
+    Full(T),
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Returns the value not sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(value) | Self::Disconnected(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("sending on a full channel"),
+            Self::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+/// Error of receiving from an empty channel whose senders have all been dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on a closed channel")
+    }
+}
+
+/// Error of receiving from a channel without sleeping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel has no value now.
+    Empty,
+    /// The channel has no value and all senders have been dropped.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("receiving on an empty channel"),
+            Self::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
@@ -1,37 +1,162 @@
 //! Handle scheduling.
+//!
+//! Each processor has its own [`RunQueue`] and an idle task, which runs when no other task is
+//! ready. A task becoming ready is queued on the least loaded processor allowed by its affinity,
+//! and a processor about to go idle steals a ready task from others.
+//!
+//! The task table, ids and timers are shared under the lock of [`TaskManager`], which is taken
+//! before run queues. A run queue is locked without it only by timer ticks of its own processor
+//! to decide whether to switch tasks, which never wait for other locks while holding it.
 
-use alloc::boxed::Box;
-use alloc::collections::VecDeque;
+use alloc::collections::{BinaryHeap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+};
 use core::arch::global_asm;
-use core::{cell::UnsafeCell, mem};
-use util::paging::PAGE_SIZE;
+use core::{
+    cell::UnsafeCell,
+    cmp::Ordering,
+    fmt::{self, Display},
+    mem,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering::*},
+    task::Waker,
+    time::Duration,
+};
 
+#[cfg(feature = "lockdep")]
+use util::lockdep::HeldLocks;
 use util::{
+    apic::{self, DeliveryMode},
     asmfunc,
     collections::HashMap,
+    error,
+    error::Result,
     sync::{InterruptFreeMutex, InterruptFreeMutexGuard},
 };
 
-use crate::memmap::PAGE_MAP;
+use crate::{
+    cpu::{self, CpuSet, PerCpu, this_cpu},
+    fpu::{self, FpuArea},
+    gdt,
+    interrupt::{RESCHED_INT_VEC, TIMER_INT_VEC},
+    paging, softirq,
+    timer::{self, Instant, TIMER_INT_FREQ},
+    tls::{self, TlsBlock},
+    workqueue,
+};
 
 const DEFAULT_STACK_SIZE_IN_PAGES: usize = 16;
 
+/// The highest priority. Tasks with higher priorities always run before ones with lower
+/// priorities.
+pub const MAX_PRIORITY: u32 = 7;
+
+/// Priority of tasks created by [`TaskManager::spawn()`].
+pub const DEFAULT_PRIORITY: u32 = 4;
+
+/// Priority of idle tasks, which run only when no other task is ready.
+const IDLE_PRIORITY: u32 = 0;
+
+const NUM_PRIORITIES: usize = MAX_PRIORITY as usize + 1;
+
+/// Time slice of tasks with [`MAX_PRIORITY`] in milliseconds. Tasks with lower priorities get
+/// longer slices because they are less likely to be interactive.
+const BASE_TIME_SLICE_MSEC: u64 = 10;
+
+/// Name of tasks spawned without one.
+const UNNAMED: &str = "<unnamed>";
+
+/// Code run by a task.
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// Code run by a task when it exits, whether [`Entry`] returns or the task is killed.
+struct OnExit(Box<dyn FnOnce() + Send>);
+
+impl fmt::Debug for OnExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnExit").finish_non_exhaustive()
+    }
+}
+
 pub static TASK_MANAGER: TaskManager = TaskManager::new();
 
-pub type TaskId = u32;
+/// Identifies a task.
+///
+/// Indices of exited tasks are reused by new tasks with the next generation, so the id of an
+/// exited task never refers to another task.
+// NOTE: The generation wraps around after an index is reused 2^32 times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TaskId {
+    index: u32,
+    generation: u32,
+}
+
+impl TaskId {
+    /// Id of the initial task, which runs on the kernel stack as the idle task of the bootstrap
+    /// processor.
+    pub const INITIAL: Self = Self {
+        index: 0,
+        generation: 0,
+    };
+
+    /// Returns the index of the id, which is unique among running tasks.
+    pub const fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns the generation of the id, which is incremented every time the index is reused.
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Packs the id into `u64` to store it in an atomic variable.
+    pub(crate) const fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    /// Unpacks the id packed by [`TaskId::to_bits()`].
+    pub(crate) const fn from_bits(bits: u64) -> Self {
+        Self {
+            index: bits as u32,
+            generation: (bits >> 32) as u32,
+        }
+    }
+}
+
+impl Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
 
 /// Managing task schedule.
-// TODO: Support multi processor.
 #[derive(Debug)]
 pub struct TaskManager {
-    /// All tasks not exited.
-    tasks: UnsafeCell<HashMap<TaskId, UnsafeCell<Task>>>,
-    /// Runnnig queue.
-    queue: UnsafeCell<VecDeque<TaskId>>,
-    /// Currently running tasks's id.
-    running_id: UnsafeCell<TaskId>,
-    /// Id that was used on last registering task.
-    head_id: UnsafeCell<TaskId>,
+    /// All tasks not exited. Boxed so that tasks do not move while their contexts are switched
+    /// without the lock.
+    tasks: UnsafeCell<HashMap<TaskId, Box<UnsafeCell<Task>>>>,
+    /// Allocator of task ids.
+    ids: UnsafeCell<IdAllocator>,
+    /// Deadlines of tasks sleeping by [`TaskManager::sleep_until()`] and futures waiting by
+    /// [`TaskManager::wake_at()`].
+    timers: UnsafeCell<BinaryHeap<Timer>>,
+    /// The earliest deadline in `timers` in nanoseconds, or `u64::MAX` if there is none. Lets
+    /// timer ticks skip locking when no deadline has passed.
+    next_deadline: AtomicU64,
+    /// Number that will be assigned to the next timer of [`TaskManager::wake_at()`].
+    next_timer_id: AtomicU64,
+    /// The number of tasks in all run queues.
+    ready: AtomicUsize,
+    /// Exited tasks whose stacks may still be in use. Freed by [`TaskManager::reap()`]. Boxed
+    /// because `on_cpu` of the last one is cleared after it is moved here.
+    #[allow(clippy::vec_box)]
+    dead: UnsafeCell<Vec<Box<UnsafeCell<Task>>>>,
     lock: InterruptFreeMutex<()>,
 }
 
@@ -42,39 +167,157 @@ impl TaskManager {
     pub const fn new() -> Self {
         Self {
             tasks: UnsafeCell::new(HashMap::new()),
-            queue: UnsafeCell::new(VecDeque::new()),
-            running_id: UnsafeCell::new(0),
-            head_id: UnsafeCell::new(0),
+            ids: UnsafeCell::new(IdAllocator::new()),
+            timers: UnsafeCell::new(BinaryHeap::new()),
+            next_deadline: AtomicU64::new(u64::MAX),
+            next_timer_id: AtomicU64::new(0),
+            ready: AtomicUsize::new(0),
+            dead: UnsafeCell::new(Vec::new()),
             lock: InterruptFreeMutex::new(()),
         }
     }
 
-    /// Initialize [`TaskManager`]. Register current task as task whose id is `0`.
+    /// Registers the code running on the current processor as its idle task, and starts
+    /// scheduling tasks on the processor.
+    ///
+    /// Call this once on each processor after [`cpu::init()`], first on the bootstrap processor so
+    /// that the initial task gets [`TaskId::INITIAL`].
     pub fn init(&self) {
-        let _lock = self.lock.lock();
+        let _lock = self.lock();
         // Safety: lock is acquired.
         let tasks = unsafe { &mut *self.tasks.get() };
-        let queue = unsafe { &mut *self.queue.get() };
-        let mut task = Task::new(0, 0);
+        let ids = unsafe { &mut *self.ids.get() };
+        let cpu = this_cpu();
+        let id = ids.allocate().unwrap();
+        debug_assert_eq!(id == TaskId::INITIAL, cpu.id() == 0);
+        let mut task = Task::new(id, format!("idle/{}", cpu.id()), IDLE_PRIORITY);
         task.state = TaskState::Running;
-        tasks.insert(0, UnsafeCell::new(task));
-        queue.push_back(0);
+        task.affinity = CpuSet::single(cpu.id());
+        task.cpu = cpu.id();
+        *task.on_cpu.get_mut() = true;
+        // The idle task is not restored to start, so switch to its thread-local storage here.
+        // Safety: The block lives as long as the task, which never exits.
+        unsafe { tls::set_fs_base(task.ctx.fs_base) };
+        tasks.insert(id, Box::new(UnsafeCell::new(task)));
+        cpu.set_task_sleeping_locks(&unsafe { self.task(id) }.unwrap().sleeping_locks);
+        #[cfg(feature = "lockdep")]
+        cpu.set_task_locks(&unsafe { self.task(id) }.unwrap().held_locks);
+
+        let mut rq = cpu.run_queue().lock();
+        rq.idle = id;
+        rq.current_priority = IDLE_PRIORITY;
+        rq.run_start_tsc = asmfunc::rdtsc();
+        rq.online = true;
+        cpu.set_current_task(id);
     }
 
     /// Register new task, whose entry point is `f` and it will run on `cs` code segment and `ss`
-    /// stack segment with `priority`.
-    pub fn register_new_task(&self, f: fn(), priority: u32, cs: u16, ss: u16) {
-        let mut lock = self.lock.lock();
-        // Safety: lock is acquired.
-        let tasks = unsafe { &mut *self.tasks.get() };
-        let queue = unsafe { &mut *self.queue.get() };
+    /// stack segment with `priority`, which is up to [`MAX_PRIORITY`]. Returns the id of the new
+    /// task.
+    ///
+    /// The task exits when `f` returns.
+    pub fn register_new_task(&self, f: fn(), priority: u32, cs: u16, ss: u16) -> Result<TaskId> {
+        self.register(Box::new(f), None, UNNAMED.to_string(), priority, cs, ss)
+    }
+
+    /// Spawns a new unnamed kernel task running `f` with [`DEFAULT_PRIORITY`], and returns the
+    /// handle to wait for its result. Use [`Builder`] to configure the task.
+    ///
+    /// ```ignore
+    /// let handle = TASK_MANAGER.spawn(move || port.read_all())?;
+    /// if let Ok(data) = handle.join() {
+    ///     parse(data);
+    /// }
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Builder::new().spawn(f)
+    }
+
+    /// Spawns a new kernel task configured by `builder`. See [`TaskManager::spawn()`].
+    fn spawn_with<F, T>(&self, builder: Builder, f: F) -> Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(InterruptFreeMutex::new(Packet {
+            result: None,
+            finished: false,
+            waiter: None,
+        }));
+        let their_packet = Arc::clone(&packet);
+        let entry = move || {
+            let result = f();
+            their_packet.lock().result = Some(Ok(result));
+        };
+        // Finish in `exit()` so that killed tasks also do.
+        let exit_packet = Arc::clone(&packet);
+        let on_exit = move || {
+            let waiter = {
+                let mut packet = exit_packet.lock();
+                packet.result.get_or_insert(Err(Killed));
+                packet.finished = true;
+                packet.waiter.take()
+            };
+            // Wake up the waiter after unlocking to keep the lock short.
+            if let Some(waiter) = waiter {
+                TASK_MANAGER.wake_up(waiter);
+            }
+        };
+        let id = self.register(
+            Box::new(entry),
+            Some(OnExit(Box::new(on_exit))),
+            builder.name.unwrap_or_else(|| UNNAMED.to_string()),
+            builder.priority,
+            gdt::KERNEL_CS,
+            gdt::KERNEL_SS,
+        )?;
+        Ok(JoinHandle { id, packet })
+    }
 
-        let new_id = self.determine_id(Some(&mut lock));
-        let mut new_task = Task::with_function(new_id, priority, f, cs, ss);
+    /// Registers a new task named `name` running `entry`, and returns its id. `on_exit` is run
+    /// when the task exits.
+    fn register(
+        &self,
+        entry: Entry,
+        on_exit: Option<OnExit>,
+        name: String,
+        priority: u32,
+        cs: u16,
+        ss: u16,
+    ) -> Result<TaskId> {
+        check_priority(priority)?;
+        self.reap();
+        let new_id = {
+            let _lock = self.lock();
+            // Safety: lock is acquired.
+            unsafe { &mut *self.ids.get() }.allocate()?
+        };
+        // Allocate the stack without the lock because it may take long.
+        let mut new_task = match Task::with_function(new_id, name, priority, entry, cs, ss) {
+            Ok(task) => task,
+            Err(e) => {
+                let _lock = self.lock();
+                // Safety: lock is acquired.
+                unsafe { &mut *self.ids.get() }.release(new_id);
+                return Err(e);
+            }
+        };
         new_task.state = TaskState::Ready;
+        new_task.cpu = this_cpu().id();
+        new_task.on_exit = on_exit;
 
-        tasks.insert(new_task.id, UnsafeCell::new(new_task));
-        queue.push_back(new_id);
+        let lock = self.lock();
+        // Safety: lock is acquired.
+        let tasks = unsafe { &mut *self.tasks.get() };
+        tasks.insert(new_task.id, Box::new(UnsafeCell::new(new_task)));
+        let target = unsafe { self.enqueue(new_id, false) };
+        drop(lock);
+        kick(target);
+        Ok(new_id)
     }
 
     /// Start task management (by enabling interrupt).
@@ -85,73 +328,334 @@ impl TaskManager {
         }
     }
 
-    /// Saves current context `prev_ctx` and switches task.
+    /// Raises the softirq waking up tasks whose deadlines have passed, accounts `ticks` timer
+    /// ticks to the current task, and switches tasks saving the current context `prev_ctx` if its
+    /// time slice has run out, a task with a higher priority is ready, or it may no longer run on
+    /// the processor. Reschedule IPIs call this with `ticks` of `0`.
     ///
     /// # Safety
     ///
     /// Call it from timer interrupt handler without enabling interrupts.
-    pub unsafe fn switch(&self, prev_ctx: &Context) {
-        // This may cause deadlock when another interrupt occurs, but it won't because caller
-        // guarantees safety requirement.
-        let mut lock = self.lock.lock();
+    pub unsafe fn tick(&self, prev_ctx: &Context, ticks: u64) {
+        let cpu = this_cpu();
+        // Timers are shared, but every processor checks them so that one running long with
+        // interrupts or preemption disabled does not delay all sleepers. Waking tasks up takes the
+        // lock of `self`, so leave it to the softirq, which checks the deadline again.
+        if Instant::now().as_nanos() >= self.next_deadline.load(Relaxed) {
+            softirq::raise(TIMER_INT_VEC);
+        }
 
-        let next_id = self.rotate(Some(&mut lock));
+        // Decide without the lock of `self` in most ticks, which switch no tasks.
+        let expired = {
+            let mut rq = cpu.run_queue().lock();
+            if !rq.online {
+                return;
+            }
+            rq.slice_left = rq.slice_left.saturating_sub(ticks);
+            rq.run_ticks += ticks;
+            let expired = rq.slice_left == 0;
+            let others_ready = self.ready.load(Relaxed) > rq.len;
+            let switch = rq.should_switch(cpu.current_task(), expired, others_ready);
+            if switch && cpu.preempt_count() > 0 {
+                // Keep the slice run out so that the reschedule on enabling preemption rotates
+                // tasks.
+                cpu.defer_preemption();
+                return;
+            }
+            if expired {
+                rq.slice_left = time_slice(rq.current_priority);
+            }
+            if !switch {
+                return;
+            }
+            expired
+        };
 
-        // Safety: lock is acquired.
-        let tasks = unsafe { &mut *self.tasks.get() };
-        let current_id = unsafe { &mut *self.running_id.get() };
-        if *current_id == next_id {
+        let lock = self.lock();
+        let mut rq = cpu.run_queue().lock();
+        rq.need_resched = false;
+        let current_id = cpu.current_task();
+        let is_idle = current_id == rq.idle;
+        // Safety: lock is acquired with interrupts disabled.
+        let current = unsafe { self.task(current_id) }.unwrap();
+        let allowed = current.affinity.contains(cpu.id());
+        let highest = rq.highest_priority();
+        let preempted = highest.is_some_and(|highest| highest > rq.current_priority);
+        // Tasks with the same priority take turns only when the slice runs out.
+        let rotated = expired && highest == Some(rq.current_priority);
+        if !is_idle && allowed && !preempted && !rotated {
             return;
         }
+        let next = match unsafe { self.pop_next(cpu, &mut rq) } {
+            Some(next) => next,
+            None if is_idle => return,
+            None => rq.idle,
+        };
 
-        let current_task = unsafe { &mut *tasks.get(current_id).unwrap().get() };
-        *current_task.ctx = prev_ctx.clone();
-
-        let next_task = unsafe { &*tasks.get(&next_id).unwrap().get() };
-        *current_id = next_task.id;
+        let fpu_area = current.ctx.fpu_area;
+        *current.ctx = prev_ctx.clone();
+        current.ctx.fpu_area = fpu_area;
+        // Safety: `fpu_area` is the address of the area of the task.
+        unsafe { fpu::save(fpu_area as *mut u8) };
+        // The interrupt handler does not save it.
+        current.ctx.fs_base = tls::fs_base();
+        current.slice_left = rq.slice_left;
+        current.state = TaskState::Ready;
+        current.account_run(&rq, false);
+        // This runs on the interrupt stack, so other processors can run the task from now on.
+        current.on_cpu.store(false, Release);
+        let next_task = unsafe { self.set_running(cpu, &mut rq, next) };
+        drop(rq);
+        let target = if is_idle {
+            None
+        } else {
+            // Resume the preempted task first among ones with the same priority.
+            unsafe { self.enqueue(current_id, !expired && allowed) }
+        };
         // We should release lock here because we can never release it after context switch. (Any
         // task never return here on the same context because `prev_ctx` is the context before
         // the interrupt occured.
         // We have to consider rece conditions, but before `IRET` instruction, IF flag is not set.
         // Since another interrupt cannot occur, race conditions do not.
         drop(lock);
+        kick(target);
 
-        restore_context(next_task.ctx.as_ref());
+        restore_context(&next_task.ctx, None, &next_task.on_cpu);
+    }
+
+    /// Wakes up tasks whose deadlines have passed. Run as the softirq of the timer.
+    pub(crate) fn wake_up_expired(&self) {
+        let now = Instant::now();
+        if now.as_nanos() < self.next_deadline.load(Relaxed) {
+            return;
+        }
+
+        let lock = self.lock();
+        // Safety: lock is acquired.
+        let timers = unsafe { &mut *self.timers.get() };
+        let mut targets = CpuSet::empty();
+        let mut wakers = Vec::new();
+        while let Some(timer) = timers.peek()
+            && timer.deadline <= now
+        {
+            match timers.pop().unwrap().target {
+                TimerTarget::Task { id, seq } => {
+                    // Safety: lock is acquired with interrupts disabled.
+                    if unsafe { self.task(id) }.is_none_or(|task| task.timer_seq != seq) {
+                        // The task has exited or cancelled the timer.
+                        continue;
+                    }
+                    if let Some(cpu) = unsafe { self.wake_up_locked(id) } {
+                        targets = targets.with(cpu.id());
+                    }
+                }
+                TimerTarget::Waker { waker, .. } => wakers.push(waker),
+            }
+        }
+        let next = timers.peek().map(|timer| timer.deadline);
+        self.next_deadline
+            .store(next.map_or(u64::MAX, |next| next.as_nanos()), Relaxed);
+        // The one-shot expiry that has just fired was the only one armed on this processor, so arm
+        // the next one. Otherwise later timers would wait for ticks.
+        if let Some(next) = next {
+            timer::set_deadline(timer::instant_to_tsc(next));
+        }
+        drop(lock);
+
+        for id in targets.iter() {
+            kick(cpu::get(id));
+        }
+        // Wakers may call `wake_up()`, which takes the lock.
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Wakes `waker` at `deadline` in the same way as [`TaskManager::sleep_until()`], and returns
+    /// the id to cancel it. Used by futures waiting for time to pass, like
+    /// [`executor::sleep()`](crate::executor::sleep).
+    pub fn wake_at(&self, deadline: Instant, waker: Waker) -> TimerId {
+        let id = TimerId(self.next_timer_id.fetch_add(1, Relaxed));
+        {
+            let _lock = self.lock();
+            // Safety: lock is acquired.
+            let timers = unsafe { &mut *self.timers.get() };
+            timers.push(Timer {
+                deadline,
+                target: TimerTarget::Waker { id, waker },
+            });
+            self.next_deadline.fetch_min(deadline.as_nanos(), Relaxed);
+        }
+        timer::set_deadline(timer::instant_to_tsc(deadline));
+        id
+    }
+
+    /// Cancels the timer registered by [`TaskManager::wake_at()`], and drops its waker. Does
+    /// nothing if it has fired.
+    pub fn cancel_timer(&self, id: TimerId) {
+        let _lock = self.lock();
+        // Safety: lock is acquired.
+        let timers = unsafe { &mut *self.timers.get() };
+        timers.retain(|timer| !matches!(timer.target, TimerTarget::Waker { id: i, .. } if i == id));
+    }
+
+    /// Changes the priority of the task whose id is `id` to `priority`, which is up to
+    /// [`MAX_PRIORITY`]. The change takes effect by the next timer tick.
+    pub fn set_priority(&self, id: TaskId, priority: u32) -> Result<()> {
+        check_priority(priority)?;
+        let lock = self.lock();
+        // Safety: lock is acquired with interrupts disabled.
+        let Some(task) = (unsafe { self.task(id) }) else {
+            error!(format!("no task whose id is {}", id));
+        };
+        let cpu = cpu::get(task.cpu).unwrap();
+        let mut rq = cpu.run_queue().lock();
+        if rq.idle == id {
+            error!("the priority of idle tasks cannot be changed");
+        }
+        let mut target = None;
+        match task.state {
+            TaskState::Ready => {
+                rq.remove(id, task.priority);
+                rq.push_back(id, priority);
+            }
+            TaskState::Running => {
+                rq.current_priority = priority;
+                rq.slice_left = rq.slice_left.min(time_slice(priority));
+                rq.need_resched = true;
+                if cpu.id() != this_cpu().id() {
+                    target = Some(cpu);
+                }
+            }
+            TaskState::Blocked => {}
+        }
+        task.priority = priority;
+        task.slice_left = task.slice_left.min(time_slice(priority));
+        drop(rq);
+        drop(lock);
+        kick(target);
+        Ok(())
+    }
+
+    /// Restricts processors the task whose id is `id` runs on to `affinity`, which must contain a
+    /// processor running tasks. The change takes effect by the next timer tick.
+    pub fn set_affinity(&self, id: TaskId, affinity: CpuSet) -> Result<()> {
+        let lock = self.lock();
+        // Safety: lock is acquired with interrupts disabled.
+        let Some(task) = (unsafe { self.task(id) }) else {
+            error!(format!("no task whose id is {}", id));
+        };
+        if !cpu::iter().any(|cpu| affinity.contains(cpu.id()) && cpu.run_queue().lock().online) {
+            error!(format!("no processor in {:?} runs tasks", affinity));
+        }
+        let cpu = cpu::get(task.cpu).unwrap();
+        let mut rq = cpu.run_queue().lock();
+        if rq.idle == id {
+            error!("the affinity of idle tasks cannot be changed");
+        }
+        task.affinity = affinity;
+        if affinity.contains(cpu.id()) {
+            return Ok(());
+        }
+
+        let mut target = None;
+        match task.state {
+            TaskState::Ready => {
+                rq.remove(id, task.priority);
+                self.ready.fetch_sub(1, Relaxed);
+            }
+            TaskState::Running => {
+                // Let the processor move it to another one.
+                rq.need_resched = true;
+                if cpu.id() != this_cpu().id() {
+                    target = Some(cpu);
+                }
+            }
+            TaskState::Blocked => {}
+        }
+        drop(rq);
+        if task.state == TaskState::Ready {
+            target = unsafe { self.enqueue(id, false) };
+        }
+        drop(lock);
+        kick(target);
+        Ok(())
     }
 
     /// Returns the id of the current task, that is the task calling this method.
     pub fn task_id(&self) -> TaskId {
-        // Safety: `self.running_id` can be changed, but changing occurs other tasks or interrupt
-        //         handler. `self.running_id` is always the same number here.
-        // TODO: We should running_id for each processors when enable multi processing.
-        unsafe { *self.running_id.get() }
+        // Disable interrupts so that the task does not move to another processor between finding
+        // the processor and reading its current task.
+        let if_is_set = asmfunc::get_if();
+        asmfunc::cli();
+        let id = this_cpu().current_task();
+        if if_is_set {
+            asmfunc::sti();
+        }
+        id
     }
 
-    /// Sleep the current task, that is the task calling this method.
-    pub fn sleep(&self) {
+    /// Returns whether the current task holds guards of sleeping locks, like
+    /// [`Mutex`](crate::sync::Mutex), whose waiters would sleep forever if it exited.
+    pub fn holds_sleeping_locks(&self) -> bool {
         let if_is_set = asmfunc::get_if();
         asmfunc::cli();
-        let mut lock = self.lock.lock();
-        // Safety: lock is acquired and itnerrupt disabled.
-        let tasks = unsafe { &mut *self.tasks.get() };
-        let queue = unsafe { &mut *self.queue.get() };
-        let current = unsafe { &mut *self.running_id.get() };
-        let current_task = unsafe { &mut *tasks.get(current).unwrap().get() };
+        let holds = this_cpu()
+            .task_sleeping_locks()
+            .is_some_and(|count| count.load(Relaxed) != 0);
+        if if_is_set {
+            asmfunc::sti();
+        }
+        holds
+    }
 
-        current_task.state = TaskState::Bloked;
-        queue.pop_front();
+    /// Returns whether the current task is the idle task of the processor, which must never
+    /// sleep or exit.
+    pub fn is_idle(&self) -> bool {
+        let if_is_set = asmfunc::get_if();
+        asmfunc::cli();
+        let cpu = this_cpu();
+        let is_idle = cpu.current_task() == cpu.run_queue().lock().idle;
+        if if_is_set {
+            asmfunc::sti();
+        }
+        is_idle
+    }
 
-        let next = self.rotate(Some(&mut lock));
-        *current = next;
-        // Safety: lock is acquired and itnerrupt disabled.
-        let next_task = unsafe { &mut *tasks.get(&next).unwrap().get() };
+    /// Sleep the current task, that is the task calling this method.
+    pub fn sleep(&self) {
+        let lock = self.lock();
+        let cpu = this_cpu();
+        debug_assert_eq!(cpu.preempt_count(), 0, "preemption is disabled");
+        let current_id = cpu.current_task();
+        // Safety: lock is acquired with interrupts disabled.
+        let current = unsafe { self.task(current_id) }.unwrap();
+
+        // Another task has already woken up the current task.
+        if mem::take(&mut current.wakeup_pending) {
+            return;
+        }
+
+        let mut rq = cpu.run_queue().lock();
+        debug_assert_ne!(current_id, rq.idle, "idle tasks cannot sleep");
+        current.state = TaskState::Blocked;
+        current.slice_left = rq.slice_left;
+        current.account_run(&rq, true);
+        let next = unsafe { self.pop_next(cpu, &mut rq) }.unwrap_or(rq.idle);
+        let next_task = unsafe { self.set_running(cpu, &mut rq, next) };
+        drop(rq);
         // We should release lock here because we can never release it after context switch. (Any
         // task never return here on the same context, because another tasks must wakes up current
         // task to return here but no tasks can acquire lock to do so.
-        // We have to consider rece conditions, but before `IRET` instruction, IF flag is not set.
-        // Since another interrupt cannot occur, race conditions do not.
-        drop(lock);
-        switch_context(&next_task.ctx, &mut current_task.ctx);
+        // Another processor may wake up and run the task before switching, but it waits for
+        // `_switch_context()` to leave the task.
+        let if_is_set = InterruptFreeMutexGuard::unlock_keeping_cli(lock);
+        switch_context(
+            &next_task.ctx,
+            &next_task.on_cpu,
+            &mut current.ctx,
+            &current.on_cpu,
+        );
 
         // Returns here if another task wakes it up.
         if if_is_set {
@@ -159,58 +663,330 @@ impl TaskManager {
         }
     }
 
-    /// Wakes up the task, whose id is `id`.
+    /// Sleeps the current task until `deadline`. The task wakes up at `deadline` in
+    /// [`Mode::TscDeadline`](timer::Mode::TscDeadline), or on the first timer tick at or after it
+    /// otherwise.
+    ///
+    /// Do not call this from idle tasks, which must never sleep.
+    pub fn sleep_until(&self, deadline: Instant) {
+        debug_assert!(!self.is_idle());
+        if Instant::now() >= deadline {
+            return;
+        }
+        {
+            let _lock = self.lock();
+            // Safety: lock is acquired with interrupts disabled.
+            let current = unsafe { self.task(this_cpu().current_task()) }.unwrap();
+            let timers = unsafe { &mut *self.timers.get() };
+            timers.push(Timer {
+                deadline,
+                target: TimerTarget::Task {
+                    id: current.id,
+                    seq: current.timer_seq,
+                },
+            });
+            self.next_deadline.fetch_min(deadline.as_nanos(), Relaxed);
+        }
+        // Fall back on ticks if one-shot expiries are not supported.
+        timer::set_deadline(timer::instant_to_tsc(deadline));
+
+        // `sleep()` may return early by `wake_up()` from others.
+        while Instant::now() < deadline {
+            self.sleep();
+        }
+
+        // Cancel the timer if others have woken the task up at the deadline before it.
+        let _lock = self.lock();
+        // Safety: lock is acquired with interrupts disabled.
+        unsafe { self.task(this_cpu().current_task()) }
+            .unwrap()
+            .timer_seq += 1;
+    }
+
+    /// Sleeps the current task for `duration`. See [`TaskManager::sleep_until()`].
+    pub fn sleep_for(&self, duration: Duration) {
+        self.sleep_until(Instant::now() + duration);
+    }
+
+    /// Yields the processor to another task ready on it with the same or a higher priority, if
+    /// any. The current task may be moved to another processor with fewer tasks.
+    pub fn yield_now(&self) {
+        let lock = self.lock();
+        let cpu = this_cpu();
+        debug_assert_eq!(cpu.preempt_count(), 0, "preemption is disabled");
+        let current_id = cpu.current_task();
+        // Safety: lock is acquired with interrupts disabled.
+        let current = unsafe { self.task(current_id) }.unwrap();
+        let mut rq = cpu.run_queue().lock();
+        let is_idle = current_id == rq.idle;
+        let current_priority = rq.current_priority;
+        if !rq
+            .highest_priority()
+            .is_some_and(|highest| is_idle || highest >= current_priority)
+        {
+            return;
+        }
+
+        // Unwrapping succeeds because the queue is not empty.
+        let next = rq.pop().unwrap();
+        self.ready.fetch_sub(1, Relaxed);
+        current.state = TaskState::Ready;
+        current.slice_left = time_slice(current.priority);
+        current.account_run(&rq, true);
+        let next_task = unsafe { self.set_running(cpu, &mut rq, next) };
+        drop(rq);
+        let target = if is_idle {
+            None
+        } else {
+            unsafe { self.enqueue(current_id, false) }
+        };
+        // Release lock before switching for the same reason as `sleep()`.
+        let if_is_set = InterruptFreeMutexGuard::unlock_keeping_cli(lock);
+        kick(target);
+        switch_context(
+            &next_task.ctx,
+            &next_task.on_cpu,
+            &mut current.ctx,
+            &current.on_cpu,
+        );
+
+        if if_is_set {
+            asmfunc::sti();
+        }
+    }
+
+    /// Wakes up the task, whose id is `id`. If the task is not sleeping, its next call to
+    /// [`TaskManager::sleep()`] returns immediately, so a wake-up just before sleeping is not
+    /// lost.
     // FIXME: Since this method disable interrupts, may reduce task switching, espescially calling
     //        much times. Consider better way.
     pub fn wake_up(&self, id: TaskId) {
-        asmfunc::cli();
-        let _lock = self.lock.lock();
-        // Safety: lock is acquierd and interrupts disabled.
-        let tasks = unsafe { &*self.tasks.get() };
-        let queue = unsafe { &mut *self.queue.get() };
+        let lock = self.lock();
+        // Safety: lock is acquierd with interrupts disabled.
+        let target = unsafe { self.wake_up_locked(id) };
+        // Callers may be in interrupt handlers or holding `InterruptFreeMutex`, so this does not
+        // enable interrupts unless they were enabled.
+        drop(lock);
+        kick(target);
+    }
+
+    /// Same as [`TaskManager::wake_up()`] but with the lock already acquired. Returns the
+    /// processor to send a reschedule IPI to, if any.
+    ///
+    /// # Safety
+    ///
+    /// `self.lock` must be acquired with interrupts disabled.
+    unsafe fn wake_up_locked(&self, id: TaskId) -> Option<&'static PerCpu> {
         // Check task existane.
-        if let Some(task) = tasks.get(&id) {
-            let task = unsafe { &mut *task.get() };
-            // Make sure that the task is sleeping to avoid the same id appeared in the queue.
-            if task.state == TaskState::Bloked {
-                task.state = TaskState::Ready;
-                queue.push_back(id);
-            }
+        let task = unsafe { self.task(id) }?;
+        // Make sure that the task is sleeping to avoid the same id appeared in the queue.
+        if task.state == TaskState::Blocked {
+            task.state = TaskState::Ready;
+            unsafe { self.enqueue(id, false) }
+        } else {
+            task.wakeup_pending = true;
+            None
         }
-        asmfunc::sti();
     }
 
-    fn rotate(&self, lock: Option<&mut InterruptFreeMutexGuard<'_, ()>>) -> TaskId {
-        let _lock = if lock.is_some() {
-            None
-        } else {
-            Some(self.lock.lock())
+    /// Exits the current task, that is the task calling this method, and switches to the next
+    /// task. Idle tasks cannot exit, and tasks cannot exit with preemption disabled.
+    ///
+    /// The task is freed later in task context because it is still running on its stack. Freeing
+    /// stacks shoots down TLB entries on all processors, so it is left to [`workqueue::SYSTEM`]
+    /// and spawning tasks rather than timer ticks.
+    ///
+    /// Nothing is unwound, so the locks held by the task are never released. Callers killing a
+    /// task, like the page fault handler, must check [`TaskManager::holds_sleeping_locks()`]
+    /// first.
+    pub fn exit(&self) -> ! {
+        assert_eq!(
+            this_cpu().preempt_count(),
+            0,
+            "task exits with preemption disabled"
+        );
+        let on_exit = {
+            let _lock = self.lock();
+            // Safety: lock is acquired with interrupts disabled.
+            unsafe { self.task(this_cpu().current_task()) }.and_then(|task| task.on_exit.take())
         };
+        // Run it without the lock because it may wake up tasks.
+        if let Some(OnExit(on_exit)) = on_exit {
+            on_exit();
+        }
+        // Frees tasks exited before. This one is freed by the next reaping once it leaves its
+        // stack.
+        if let Some(wq) = workqueue::SYSTEM.try_get() {
+            wq.queue(|| TASK_MANAGER.reap());
+        }
 
-        let tasks = unsafe { &mut *self.tasks.get() };
-        let queue = unsafe { &mut *self.queue.get() };
-        let current_id = queue.pop_front().unwrap();
-        unsafe { (*tasks.get(&current_id).unwrap().get()).state = TaskState::Ready };
-        queue.push_back(current_id);
-        let next_id = queue.front().copied().unwrap();
-        unsafe { (*tasks.get(&next_id).unwrap().get()).state = TaskState::Running };
-        next_id
-    }
-
-    fn determine_id(&self, lock: Option<&mut InterruptFreeMutexGuard<'_, ()>>) -> TaskId {
-        let _lock = match lock {
-            Some(_) => None,
-            None => Some(self.lock.lock()),
+        let lock = self.lock();
+        let cpu = this_cpu();
+        let current_id = cpu.current_task();
+        let mut rq = cpu.run_queue().lock();
+        assert_ne!(current_id, rq.idle, "idle tasks cannot exit");
+
+        // Safety: lock is acquired with interrupts disabled.
+        let task = unsafe { &mut *self.tasks.get() }
+            .remove(&current_id)
+            .unwrap();
+        unsafe { &mut *self.ids.get() }.release(current_id);
+        let next = unsafe { self.pop_next(cpu, &mut rq) }.unwrap_or(rq.idle);
+        let next_task = unsafe { self.set_running(cpu, &mut rq, next) };
+        drop(rq);
+        // The task is boxed, so this stays valid after moving it.
+        let on_cpu: *const AtomicBool = unsafe { &(*task.get()).on_cpu };
+        unsafe { &mut *self.dead.get() }.push(task);
+        // Release lock here because we never return. The exited task is not freed before
+        // `_restore_context()` leaves its stack and clears `on_cpu`.
+        InterruptFreeMutexGuard::unlock_keeping_cli(lock);
+        restore_context(&next_task.ctx, Some(unsafe { &*on_cpu }), &next_task.on_cpu);
+
+        unreachable!("restored context returned");
+    }
+
+    /// Returns the statistics of all tasks not exited, ordered by id.
+    pub fn snapshot(&self) -> Vec<TaskInfo> {
+        let _lock = self.lock();
+        // Safety: lock is acquired with interrupts disabled.
+        let tasks = unsafe { &*self.tasks.get() };
+        let mut infos: Vec<_> = tasks
+            .values()
+            .map(|task| {
+                let task = unsafe { &*task.get() };
+                let mut info = TaskInfo {
+                    id: task.id,
+                    name: task.name.clone(),
+                    state: task.state,
+                    priority: task.priority,
+                    cpu: task.cpu,
+                    started: task.started,
+                    ticks: task.ticks,
+                    tsc: task.tsc,
+                    voluntary_switches: task.voluntary_switches,
+                    involuntary_switches: task.involuntary_switches,
+                };
+                // Running tasks are accounted when they are switched out, so add the current run.
+                if task.state == TaskState::Running {
+                    let cpu = cpu::get(task.cpu).unwrap();
+                    let rq = cpu.run_queue().lock();
+                    info.ticks += rq.run_ticks;
+                    info.tsc += asmfunc::rdtsc().saturating_sub(rq.run_start_tsc);
+                }
+                info
+            })
+            .collect();
+        infos.sort_unstable_by_key(|info| (info.id.index, info.id.generation));
+        infos
+    }
+
+    /// Frees exited tasks with their stacks.
+    fn reap(&self) {
+        let dead = {
+            let _lock = self.lock();
+            // Safety: lock is acquired.
+            let dead = unsafe { &mut *self.dead.get() };
+            // Tasks whose stacks are still in use are freed next time.
+            let (dead, in_use): (Vec<_>, _) = mem::take(dead)
+                .into_iter()
+                .partition(|task| !unsafe { &*task.get() }.on_cpu.load(Acquire));
+            *unsafe { &mut *self.dead.get() } = in_use;
+            dead
         };
+        // Freeing stacks may take long, so do it without the lock.
+        drop(dead);
+    }
+
+    /// Disables interrupts and acquires the lock. IF is restored when the returned guard is
+    /// dropped.
+    fn lock(&self) -> InterruptFreeMutexGuard<'_, ()> {
+        self.lock.lock()
+    }
+
+    /// Returns the task whose id is `id`.
+    ///
+    /// # Safety
+    ///
+    /// `self.lock` must be acquired with interrupts disabled, and no other reference to the task
+    /// may be used at the same time.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn task(&self, id: TaskId) -> Option<&mut Task> {
+        let tasks = unsafe { &*self.tasks.get() };
+        tasks.get(&id).map(|task| unsafe { &mut *task.get() })
+    }
+
+    /// Queues the ready task whose id is `id` on the least loaded processor allowed by its
+    /// affinity, at the front of its priority if `front`. Returns the processor to send a
+    /// reschedule IPI to if the task should preempt the running one there.
+    ///
+    /// # Safety
+    ///
+    /// `self.lock` must be acquired with interrupts disabled, and no run queue may be locked.
+    unsafe fn enqueue(&self, id: TaskId, front: bool) -> Option<&'static PerCpu> {
+        let task = unsafe { self.task(id) }.unwrap();
+        let cpu = select_cpu(task);
+        task.cpu = cpu.id();
+        let mut rq = cpu.run_queue().lock();
+        if front {
+            rq.push_front(id, task.priority);
+        } else {
+            rq.push_back(id, task.priority);
+        }
+        self.ready.fetch_add(1, Relaxed);
+        let preempts = cpu.current_task() == rq.idle || task.priority > rq.current_priority;
+        // The current processor checks its queue on the next tick.
+        (preempts && cpu.id() != this_cpu().id()).then_some(cpu)
+    }
+
+    /// Removes and returns the task to run next on `cpu` from its run queue `rq`, or steals one
+    /// from another processor if `rq` is empty. Returns `None` if no task can run on `cpu`.
+    ///
+    /// # Safety
+    ///
+    /// `self.lock` must be acquired with interrupts disabled, and `rq` must be locked.
+    unsafe fn pop_next(&self, cpu: &PerCpu, rq: &mut RunQueue) -> Option<TaskId> {
+        if let Some(id) = rq.pop() {
+            self.ready.fetch_sub(1, Relaxed);
+            return Some(id);
+        }
+        if self.ready.load(Relaxed) == 0 {
+            return None;
+        }
 
-        let head_id = unsafe { &mut *self.head_id.get() };
-        match head_id.checked_add(1) {
-            Some(next) => {
-                *head_id = next;
-                next
+        // Locking other run queues does not deadlock because only holders of the lock of `self`
+        // lock more than one.
+        for victim in cpu::iter().filter(|victim| victim.id() != cpu.id()) {
+            let stolen = victim.run_queue().lock().steal(|id| {
+                let task = unsafe { self.task(id) }.unwrap();
+                task.affinity.contains(cpu.id())
+            });
+            if stolen.is_some() {
+                self.ready.fetch_sub(1, Relaxed);
+                return stolen;
             }
-            None => todo!("Not supported that the number of registerd tasks exceeds 0xFFFF_FFFF."),
         }
+        None
+    }
+
+    /// Makes the task whose id is `id` run on `cpu`, whose run queue is `rq`, and returns it.
+    ///
+    /// # Safety
+    ///
+    /// `self.lock` must be acquired with interrupts disabled, and `rq` must be locked.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn set_running(&self, cpu: &PerCpu, rq: &mut RunQueue, id: TaskId) -> &mut Task {
+        let task = unsafe { self.task(id) }.unwrap();
+        task.state = TaskState::Running;
+        task.cpu = cpu.id();
+        rq.current_priority = task.priority;
+        rq.slice_left = task.slice_left;
+        rq.run_ticks = 0;
+        rq.run_start_tsc = asmfunc::rdtsc();
+        cpu.set_current_task(id);
+        cpu.set_task_sleeping_locks(&task.sleeping_locks);
+        #[cfg(feature = "lockdep")]
+        cpu.set_task_locks(&task.held_locks);
+        task
     }
 }
 
@@ -220,52 +996,533 @@ impl Default for TaskManager {
     }
 }
 
+/// Returns the processor allowed by the affinity of `task` with the fewest tasks, preferring the
+/// one it ran on last.
+fn select_cpu(task: &Task) -> &'static PerCpu {
+    let mut best: Option<(&'static PerCpu, usize)> = None;
+    for cpu in cpu::iter().filter(|cpu| task.affinity.contains(cpu.id())) {
+        let rq = cpu.run_queue().lock();
+        if !rq.online {
+            continue;
+        }
+        let load = rq.len + usize::from(cpu.current_task() != rq.idle);
+        drop(rq);
+        if best.is_none_or(|(_, best_load)| {
+            load < best_load || (load == best_load && cpu.id() == task.cpu)
+        }) {
+            best = Some((cpu, load));
+        }
+    }
+    // `TaskManager::set_affinity()` ensures that a processor running tasks is allowed.
+    best.map_or_else(this_cpu, |(cpu, _)| cpu)
+}
+
+/// Sends a reschedule IPI to `cpu`, if any.
+fn kick(cpu: Option<&PerCpu>) {
+    if let Some(cpu) = cpu {
+        apic::send_ipi(cpu.apic_id(), DeliveryMode::Fixed, RESCHED_INT_VEC);
+    }
+}
+
+/// Handles reschedule IPIs sent when a task that should preempt the current one is queued.
+#[unsafe(no_mangle)]
+pub fn _int_handler_resched(prev_ctx: &Context) {
+    apic::notify_end_of_interrupt();
+    // Safety: This is in interrupt handler and IF is not set.
+    unsafe { TASK_MANAGER.tick(prev_ctx, 0) };
+}
+
+/// Ready tasks of a processor queued by priority, and the state of the running task needed to
+/// decide whether to switch tasks.
+#[derive(Debug)]
+pub(crate) struct RunQueue {
+    /// Queues indexed by priority. The running task is not included.
+    levels: [VecDeque<TaskId>; NUM_PRIORITIES],
+    /// The number of tasks in `levels`.
+    len: usize,
+    /// Idle task of the processor, which is never queued.
+    idle: TaskId,
+    /// Priority of the running task.
+    current_priority: u32,
+    /// Ticks left until the running task yields to others with the same priority.
+    slice_left: u64,
+    /// Ticks elapsed since the running task was switched in.
+    run_ticks: u64,
+    /// TSC value when the running task was switched in.
+    run_start_tsc: u64,
+    /// Whether the running task has changed its priority or affinity and should be rescheduled.
+    need_resched: bool,
+    /// Whether the processor runs tasks.
+    online: bool,
+}
+
+impl RunQueue {
+    pub(crate) const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; NUM_PRIORITIES],
+            len: 0,
+            idle: TaskId::INITIAL,
+            current_priority: IDLE_PRIORITY,
+            slice_left: 0,
+            run_ticks: 0,
+            run_start_tsc: 0,
+            need_resched: false,
+            online: false,
+        }
+    }
+
+    fn push_back(&mut self, id: TaskId, priority: u32) {
+        self.levels[priority as usize].push_back(id);
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, id: TaskId, priority: u32) {
+        self.levels[priority as usize].push_front(id);
+        self.len += 1;
+    }
+
+    /// Removes and returns the first task with the highest priority.
+    fn pop(&mut self) -> Option<TaskId> {
+        let id = self.levels.iter_mut().rev().find_map(VecDeque::pop_front)?;
+        self.len -= 1;
+        Some(id)
+    }
+
+    /// Removes and returns the last task with the highest priority among ones `f` returns `true`
+    /// for. The last one is the least likely to have its data in the cache of the processor.
+    fn steal(&mut self, mut f: impl FnMut(TaskId) -> bool) -> Option<TaskId> {
+        for level in self.levels.iter_mut().rev() {
+            if let Some(index) = level.iter().rposition(|&id| f(id)) {
+                self.len -= 1;
+                return level.remove(index);
+            }
+        }
+        None
+    }
+
+    fn remove(&mut self, id: TaskId, priority: u32) {
+        let level = &mut self.levels[priority as usize];
+        if let Some(index) = level.iter().position(|&queued| queued == id) {
+            level.remove(index);
+            self.len -= 1;
+        }
+    }
+
+    /// Returns the highest priority of queued tasks.
+    fn highest_priority(&self) -> Option<u32> {
+        self.levels
+            .iter()
+            .rposition(|level| !level.is_empty())
+            .map(|priority| priority as u32)
+    }
+
+    /// Returns whether the running task `current` should give the processor to another task.
+    /// `expired` is whether its time slice has run out, and `others_ready` is whether other
+    /// processors have ready tasks, which an idle processor can steal.
+    fn should_switch(&self, current: TaskId, expired: bool, others_ready: bool) -> bool {
+        if current == self.idle {
+            return self.len > 0 || others_ready;
+        }
+        if self.need_resched {
+            return true;
+        }
+        self.highest_priority().is_some_and(|highest| {
+            highest > self.current_priority || (expired && highest == self.current_priority)
+        })
+    }
+}
+
+/// Deadline of a sleeping task or a future. Ordered only by deadlines so that [`BinaryHeap`] pops
+/// the earliest one first.
+#[derive(Debug)]
+struct Timer {
+    deadline: Instant,
+    target: TimerTarget,
+}
+
+/// What a [`Timer`] wakes up.
+#[derive(Debug)]
+enum TimerTarget {
+    /// A task sleeping by [`TaskManager::sleep_until()`], unless its [`Task::timer_seq`] has
+    /// changed from `seq`.
+    Task { id: TaskId, seq: u64 },
+    /// A future waiting by [`TaskManager::wake_at()`].
+    Waker { id: TimerId, waker: Waker },
+}
+
+/// Identifies a timer registered by [`TaskManager::wake_at()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Timer {}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Returns the time slice of tasks with `priority` in ticks.
+fn time_slice(priority: u32) -> u64 {
+    let msec = BASE_TIME_SLICE_MSEC * (NUM_PRIORITIES as u64 - priority as u64);
+    (msec * TIMER_INT_FREQ as u64 / 1000).max(1)
+}
+
+fn check_priority(priority: u32) -> Result<()> {
+    if priority > MAX_PRIORITY {
+        error!(format!(
+            "priority {} exceeds the maximum {}",
+            priority, MAX_PRIORITY
+        ));
+    }
+    Ok(())
+}
+
+/// Configures a new kernel task.
+///
+/// ```ignore
+/// let handle = Builder::new().name("usb").priority(MAX_PRIORITY).spawn(poll_usb)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder {
+    name: Option<String>,
+    priority: u32,
+}
+
+impl Builder {
+    /// Constructs a builder of an unnamed task with [`DEFAULT_PRIORITY`].
+    pub fn new() -> Self {
+        Self {
+            name: None,
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+
+    /// Names the task. The name is shown in [`TaskManager::snapshot()`].
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the priority of the task, which is up to [`MAX_PRIORITY`].
+    pub fn priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawns the task running `f`, and returns the handle to wait for its result. See
+    /// [`TaskManager::spawn()`].
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        TASK_MANAGER.spawn_with(self, f)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle to wait for a task spawned by [`TaskManager::spawn()`] to finish. The task keeps running
+/// even if this is dropped.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    id: TaskId,
+    packet: Arc<InterruptFreeMutex<Packet<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the id of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns whether the task has finished or been killed.
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().finished
+    }
+
+    /// Waits for the task to finish, and returns its result, or [`Killed`] if the task has been
+    /// killed, e.g. by a page fault.
+    ///
+    /// Do not call this from idle tasks, which must never sleep.
+    pub fn join(self) -> core::result::Result<T, Killed> {
+        debug_assert!(!TASK_MANAGER.is_idle());
+        loop {
+            {
+                let mut packet = self.packet.lock();
+                if packet.finished {
+                    return packet.result.take().unwrap();
+                }
+                packet.waiter = Some(TASK_MANAGER.task_id());
+            }
+            TASK_MANAGER.sleep();
+        }
+    }
+}
+
+/// State shared by a spawned task and its [`JoinHandle`].
+#[derive(Debug)]
+struct Packet<T> {
+    /// The value returned by the task, or [`Killed`] if it did not return.
+    result: Option<core::result::Result<T, Killed>>,
+    /// Whether the task has exited. `result` is set once this is set.
+    finished: bool,
+    /// The task waiting for the result.
+    waiter: Option<TaskId>,
+}
+
+/// Error of joining a task killed before returning its result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Killed;
+
+impl fmt::Display for Killed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task was killed")
+    }
+}
+
+/// Allocates task ids, reusing indices of exited tasks with the next generation.
+#[derive(Debug)]
+struct IdAllocator {
+    /// Current generation of each index allocated once.
+    generations: Vec<u32>,
+    /// Indices not used by any task.
+    free: Vec<u32>,
+}
+
+impl IdAllocator {
+    const fn new() -> Self {
+        Self {
+            generations: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns an id not used by any task.
+    fn allocate(&mut self) -> Result<TaskId> {
+        if let Some(index) = self.free.pop() {
+            return Ok(TaskId {
+                index,
+                generation: self.generations[index as usize],
+            });
+        }
+        let Ok(index) = u32::try_from(self.generations.len()) else {
+            error!("Too many tasks.");
+        };
+        self.generations.push(0);
+        Ok(TaskId {
+            index,
+            generation: 0,
+        })
+    }
+
+    /// Makes the index of `id` reusable with the next generation.
+    fn release(&mut self, id: TaskId) {
+        let generation = &mut self.generations[id.index as usize];
+        debug_assert_eq!(*generation, id.generation);
+        *generation = generation.wrapping_add(1);
+        self.free.push(id.index);
+    }
+}
+
+/// State of a task.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum TaskState {
+pub enum TaskState {
     /// Represents the task is currently running.
     Running,
     /// Not running but in queue.
     Ready,
     /// Not listed in queue. Call [`TaskManager::wake_up()`] to running this task.
-    Bloked,
+    Blocked,
+}
+
+/// Statistics of a task returned by [`TaskManager::snapshot()`].
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub state: TaskState,
+    pub priority: u32,
+    /// Index of the processor the task runs or is queued on, or ran on last.
+    pub cpu: usize,
+    /// When the task was created.
+    pub started: Instant,
+    /// Timer ticks elapsed while the task ran.
+    pub ticks: u64,
+    /// TSC counts elapsed while the task ran.
+    pub tsc: u64,
+    /// How many times the task gave up the processor by sleeping or yielding.
+    pub voluntary_switches: u64,
+    /// How many times the task was preempted.
+    pub involuntary_switches: u64,
+}
+
+impl TaskInfo {
+    /// Returns how long the task has run.
+    pub fn runtime(&self) -> Duration {
+        timer::tsc_to_duration(self.tsc)
+    }
 }
 
 #[derive(Debug)]
 pub struct Task {
     id: TaskId,
+    name: String,
     state: TaskState,
+    /// When the task was created.
+    started: Instant,
+    /// Timer ticks elapsed while the task ran, except the current run.
+    ticks: u64,
+    /// TSC counts elapsed while the task ran, except the current run.
+    tsc: u64,
+    /// How many times the task gave up the processor by sleeping or yielding.
+    voluntary_switches: u64,
+    /// How many times the task was preempted.
+    involuntary_switches: u64,
+    /// Whether [`TaskManager::wake_up()`] is called while the task is not sleeping.
+    wakeup_pending: bool,
+    /// Incremented to cancel the timer of [`TaskManager::sleep_until()`].
+    timer_seq: u64,
     // Should be saved in ProcessManager?
-    _priority: u32,
+    priority: u32,
+    /// Ticks left until the task yields to others with the same priority.
+    slice_left: u64,
+    /// Processors the task may run on.
+    affinity: CpuSet,
+    /// Index of the processor the task runs or is queued on, or ran on last.
+    cpu: usize,
+    /// Whether a processor runs on the stack of the task. Cleared after `ctx` is saved and the
+    /// processor leaves the stack, so that another one can resume the task.
+    on_cpu: AtomicBool,
     ctx: Box<Context>,
-    _stack: Stack,
+    /// Where `ctx` saves FPU, SSE and AVX registers.
+    _fpu: FpuArea,
+    /// Thread-local storage pointed to by `ctx.fs_base`.
+    _tls: TlsBlock,
+    /// `None` for idle tasks, which run on the stacks their processors booted with.
+    _stack: Option<Stack>,
+    /// Run by [`TaskManager::exit()`].
+    on_exit: Option<OnExit>,
+    /// The number of guards of sleeping locks, like [`Mutex`](crate::sync::Mutex), the task holds.
+    /// Their waiters would sleep forever if the task was killed.
+    sleeping_locks: AtomicU32,
+    /// Locks the task holds, which may be held while sleeping.
+    #[cfg(feature = "lockdep")]
+    held_locks: HeldLocks,
 }
 
 impl Task {
-    pub fn new(id: TaskId, priority: u32) -> Self {
+    pub fn new(id: TaskId, name: String, priority: u32) -> Self {
+        let fpu = FpuArea::new();
+        let tls = TlsBlock::new();
+        let mut ctx = Context::new();
+        ctx.fpu_area = fpu.as_ptr() as u64;
+        ctx.fs_base = tls.thread_pointer();
         Self {
             id,
-            state: TaskState::Bloked,
-            _priority: priority,
-            ctx: Box::new(Context::new()),
-            _stack: Stack::new(0),
+            name,
+            state: TaskState::Blocked,
+            started: Instant::now(),
+            ticks: 0,
+            tsc: 0,
+            voluntary_switches: 0,
+            involuntary_switches: 0,
+            wakeup_pending: false,
+            timer_seq: 0,
+            priority,
+            slice_left: time_slice(priority),
+            affinity: CpuSet::all(),
+            cpu: 0,
+            on_cpu: AtomicBool::new(false),
+            ctx: Box::new(ctx),
+            _fpu: fpu,
+            _tls: tls,
+            _stack: None,
+            on_exit: None,
+            sleeping_locks: AtomicU32::new(0),
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
         }
     }
 
-    pub fn with_function(id: TaskId, priority: u32, f: fn(), cs: u16, ss: u16) -> Self {
+    pub fn with_function(
+        id: TaskId,
+        name: String,
+        priority: u32,
+        entry: Entry,
+        cs: u16,
+        ss: u16,
+    ) -> Result<Self> {
         let mut ctx = Context::new();
-        let stack = Stack::new(DEFAULT_STACK_SIZE_IN_PAGES);
+        let stack = Stack::new(DEFAULT_STACK_SIZE_IN_PAGES, id)?;
         ctx.cr3 = asmfunc::get_cr3();
-        ctx.rip = f as usize as _;
+        // `task_entry` calls `entry` and exits the task when it returns. Box it again to pass a
+        // thin pointer.
+        ctx.rip = task_entry as *const () as u64;
+        ctx.rdi = Box::into_raw(Box::new(entry)) as u64;
+        // Aligned as if `task_entry` was called. The return address is left null to terminate
+        // backtraces.
         ctx.rsp = stack.as_end_ptr() as u64 - 8;
         ctx.cs = cs as _;
         ctx.ss = ss as _;
         ctx.rflags = 0x202;
-        Self {
+        let fpu = FpuArea::new();
+        ctx.fpu_area = fpu.as_ptr() as u64;
+        let tls = TlsBlock::new();
+        ctx.fs_base = tls.thread_pointer();
+        Ok(Self {
             id,
-            state: TaskState::Bloked,
-            _priority: priority,
+            name,
+            state: TaskState::Blocked,
+            started: Instant::now(),
+            ticks: 0,
+            tsc: 0,
+            voluntary_switches: 0,
+            involuntary_switches: 0,
+            wakeup_pending: false,
+            timer_seq: 0,
+            priority,
+            slice_left: time_slice(priority),
+            affinity: CpuSet::all(),
+            cpu: 0,
+            on_cpu: AtomicBool::new(false),
             ctx: Box::new(ctx),
-            _stack: stack,
+            _fpu: fpu,
+            _tls: tls,
+            _stack: Some(stack),
+            on_exit: None,
+            sleeping_locks: AtomicU32::new(0),
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
+        })
+    }
+
+    /// Adds the run recorded in `rq` to the statistics of the task when it is switched out.
+    /// `voluntary` is whether it sleeps or yields rather than being preempted.
+    fn account_run(&mut self, rq: &RunQueue, voluntary: bool) {
+        self.ticks += rq.run_ticks;
+        self.tsc += asmfunc::rdtsc().saturating_sub(rq.run_start_tsc);
+        if voluntary {
+            self.voluntary_switches += 1;
+        } else {
+            self.involuntary_switches += 1;
         }
     }
 }
@@ -322,6 +1579,10 @@ pub struct Context {
     pub r14: u64,
     /// R15
     pub r15: u64,
+    /// Address of the [`FpuArea`] where FPU, SSE and AVX registers are saved.
+    pub fpu_area: u64,
+    /// FS base, which points to the [`TlsBlock`] of the task.
+    pub fs_base: u64,
 }
 
 impl Context {
@@ -338,40 +1599,72 @@ impl Default for Context {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Stack of a task, with an unmapped guard page below it to detect overflows.
+#[derive(Debug)]
 struct Stack {
-    start: *mut u8,
-    page_count: usize,
+    range: Range<u64>,
 }
 
 impl Stack {
-    fn new(page_count: usize) -> Self {
-        let start = PAGE_MAP.allocate(page_count);
-        Self { start, page_count }
+    /// Allocates a stack of `page_count` pages owned by the task whose id is `owner`.
+    fn new(page_count: usize, owner: TaskId) -> Result<Self> {
+        let range = paging::reserve_stack(page_count, "task stack", Some(owner))?;
+        Ok(Self { range })
     }
 
-    fn as_ptr(&self) -> *const u8 {
-        self.start
+    fn as_end_ptr(&self) -> *const u8 {
+        self.range.end as _
     }
+}
 
-    fn as_end_ptr(&self) -> *const u8 {
-        unsafe { self.as_ptr().byte_add(self.page_count * PAGE_SIZE) }
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // Safety: the task using the stack has exited and left it.
+        if let Err(e) = unsafe { paging::release_stack(self.range.clone()) } {
+            log::error!("failed to free a task stack: {}", e);
+        }
     }
 }
 
+/// Entry point of tasks, which calls `entry` passed in RDI and exits the task when it returns.
+extern "sysv64" fn task_entry(entry: *mut Entry) -> ! {
+    // Safety: `Task::with_function()` passes a pointer from `Box::into_raw()`, and each task
+    //         starts only once.
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    TASK_MANAGER.exit();
+}
+
 /// Switch context from `current` to `next`.
-fn switch_context(next: &Context, current: &mut Context) {
-    unsafe { _switch_context(next, current) };
+///
+/// `current_on_cpu` is cleared after the processor leaves the stack of `current`. Then this waits
+/// until `next_on_cpu` is cleared by the processor that ran `next` last, and sets it.
+fn switch_context(
+    next: &Context,
+    next_on_cpu: &AtomicBool,
+    current: &mut Context,
+    current_on_cpu: &AtomicBool,
+) {
+    unsafe { _switch_context(next, current, current_on_cpu, next_on_cpu) };
 }
 
 /// Restores saved context `next`.
-fn restore_context(next: &Context) {
-    unsafe { _restore_context(next) };
+///
+/// `prev_on_cpu`, if any, is cleared because the current stack is no longer used. Then this waits
+/// until `next_on_cpu` is cleared by the processor that ran `next` last, and sets it.
+fn restore_context(next: &Context, prev_on_cpu: Option<&AtomicBool>, next_on_cpu: &AtomicBool) {
+    let prev_on_cpu = prev_on_cpu.map_or(ptr::null(), |on_cpu| on_cpu as *const AtomicBool);
+    unsafe { _restore_context(next, prev_on_cpu, next_on_cpu) };
 }
 
 unsafe extern "sysv64" {
-    fn _switch_context(next: &Context, current: &mut Context);
-    fn _restore_context(next: &Context);
+    fn _switch_context(
+        next: &Context,
+        current: &mut Context,
+        current_on_cpu: &AtomicBool,
+        next_on_cpu: &AtomicBool,
+    );
+    fn _restore_context(next: &Context, prev_on_cpu: *const AtomicBool, next_on_cpu: &AtomicBool);
 }
 
 global_asm! { r#"
@@ -414,12 +1707,71 @@ _switch_context:
     mov [rsi + 0xb0], r14
     mov [rsi + 0xb8], r15
 
+    mov r8, rdx
+    mov r9, rcx
+    mov rcx, [rsi + 0xc0] # FPU area
+    mov eax, -1
+    mov edx, -1
+    cmp byte ptr [rip + {use_xsave}], 0
+    je 4f
+    xsave64 [rcx]
+    jmp 5f
+4:
+    fxsave64 [rcx]
+5:
+
+    cmp byte ptr [rip + {use_fsgsbase}], 0
+    je 6f
+    rdfsbase rax
+    jmp 7f
+6:
+    mov ecx, {ia32_fs_base}
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+7:
+    mov [rsi + 0xc8], rax # FS base
+
+    mov rsi, r8
+    mov rdx, r9
     # Fall through to _restore_context
 
 .global _restore_context
 _restore_context:
 
-    # Constructs the next context frame.
+    # Let other processors resume the previous task. Do not touch the current stack from here,
+    # which may be the previous task's. Interrupts are disabled, and exceptions use IST stacks.
+    test rsi, rsi
+    jz 1f
+    mov byte ptr [rsi], 0
+1:
+
+    # Wait for the processor that ran the next task last to leave its stack. Checking it before
+    # clearing the previous one could deadlock with a processor switching the other way.
+    mov al, 1
+2:
+    xchg al, [rdx]
+    test al, al
+    jz 3f
+    pause
+    mov al, 1
+    jmp 2b
+3:
+
+    mov rcx, [rdi + 0xc0] # FPU area
+    mov eax, -1
+    mov edx, -1
+    cmp byte ptr [rip + {use_xsave}], 0
+    je 4f
+    xrstor64 [rcx]
+    jmp 5f
+4:
+    fxrstor64 [rcx]
+5:
+
+    # Constructs the next context frame on the next stack. The area below the saved RSP is unused
+    # because the kernel has no red zone.
+    mov rsp, [rdi + 0x70]
     push qword ptr [rdi + 0x28] # SS
     push qword ptr [rdi + 0x70] # RSP
     push qword ptr [rdi + 0x10] # RFLAGS
@@ -436,11 +1788,23 @@ _restore_context:
     mov r8, [rdi + 0x80]
     mov rbp, [rdi + 0x78]
 
-    mov rax, [rdi + 0x38] # GS
+    # GS is not restored because loading it clears GS base pointing to per-CPU data.
     mov rbx, [rdi + 0x30] # FS
-    mov gs, ax
     mov fs, bx
 
+    # Loading FS above clears FS base, so restore it after that.
+    mov rax, [rdi + 0xc8] # FS base
+    cmp byte ptr [rip + {use_fsgsbase}], 0
+    je 6f
+    wrfsbase rax
+    jmp 7f
+6:
+    mov rdx, rax
+    shr rdx, 32
+    mov ecx, {ia32_fs_base}
+    wrmsr
+7:
+
     mov rax, [rdi + 0x00] # CR3
     mov cr3, rax
 
@@ -453,5 +1817,8 @@ _restore_context:
     mov rdi, [rdi + 0x60]
 
     iretq
-"#
+"#,
+    use_xsave = sym fpu::USE_XSAVE,
+    use_fsgsbase = sym tls::USE_FSGSBASE,
+    ia32_fs_base = const tls::IA32_FS_BASE,
 }
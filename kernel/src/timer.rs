@@ -1,76 +1,309 @@
-use core::sync::atomic::{AtomicU64, Ordering::*};
+use core::{
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering::*},
+    time::Duration,
+};
 
-use util::{apic, asmfunc, bitfield::BitField as _, error::Result, sync::OnceStatic};
+use util::{
+    apic::{self, TimerMode},
+    asmfunc,
+    bitfield::BitField as _,
+    error::Result,
+    sync::{Lazy, OnceStatic},
+};
 
 use crate::{
     acpi::FADT,
+    cpu::this_cpu,
     interrupt::TIMER_INT_VEC,
+    softirq,
     task::{Context, TASK_MANAGER},
 };
 
 /// Timer interrupt frequency in Hz.
-// NOTE: It should be greater than `TASK_SWITCH_FREQ`. Otherwise, some task swithing would be
-//       skiped.
 pub const TIMER_INT_FREQ: u32 = 1000;
 
-/// Task swithing frequency in Hz.
-pub const TASK_SWITCH_FREQ: u32 = 5;
-
 // NOTE: We define it as `u64` to avoid overflowing when calculating how many count wait for
 // `wait_for_msec`.
 const PM_TIMER_FREQ: u64 = 3579545;
 
-pub static APIC_TIMER_FREQ: OnceStatic<u32> = OnceStatic::new();
+/// Local APIC timer frequency in Hz with the divide configuration of `0`. Measured on first use,
+/// which only [`Mode::Periodic`] needs. Takes 100 ms with ACPI PM timer, so call [`acpi::init()`]
+/// first.
+///
+/// [`acpi::init()`]: crate::acpi::init
+pub static APIC_TIMER_FREQ: Lazy<u32> = Lazy::new(|| {
+    apic::set_divide_config(0);
+    apic::start_count();
+    // We want to measure 1s, but it would spend much time, so measure 0.1s instead.
+    wait_for_msec(100);
+    let elapsed = apic::elapsed_count();
+    apic::stop_count();
+    elapsed * 10
+});
+
+/// TSC frequency in Hz.
+pub static TSC_FREQ: OnceStatic<u64> = OnceStatic::new();
 
-static COUNT: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer mode the kernel runs with.
+pub static MODE: OnceStatic<Mode> = OnceStatic::new();
 
-static PREV_INT_TSC: AtomicU64 = AtomicU64::new(0);
+/// Represents how the kernel generates timer interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The Local APIC timer fires every tick in periodic mode.
+    Periodic,
+    /// The Local APIC timer runs in TSC-deadline mode. Ticks are emulated by arming the deadline
+    /// of the next tick every time, and one-shot expiries can be armed by [`set_deadline()`].
+    TscDeadline,
+}
+
+/// TSC value when the timer started. [`get_timestamp()`] counts from it.
+static BOOT_TSC: OnceStatic<u64> = OnceStatic::new();
+
+/// Timer state owned by each processor.
+#[derive(Debug)]
+pub struct TimerState {
+    /// How many ticks have elapsed on the processor.
+    ticks: AtomicU64,
+    /// TSC value at which the next tick is due. Used only in [`Mode::TscDeadline`].
+    next_tick_tsc: AtomicU64,
+    /// The earliest one-shot expiry requested by [`set_deadline()`], or `u64::MAX` if there is
+    /// none. Later ones are not kept.
+    oneshot_tsc: AtomicU64,
+}
+
+impl TimerState {
+    /// Constructs a state with no ticks elapsed.
+    pub const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            next_tick_tsc: AtomicU64::new(0),
+            oneshot_tsc: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Returns how many ticks have elapsed on the processor.
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Relaxed)
+    }
+}
 
-static CURRENT_INT_TSC: AtomicU64 = AtomicU64::new(0);
+impl Default for TimerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub fn init() -> Result<()> {
-    apic::set_divide_config(0);
-    apic::start_count();
+    let tsc_start = asmfunc::rdtsc();
+    BOOT_TSC.init(tsc_start);
     // We want to measure 1s, but it would spend much time, so measure 0.1s instead.
     wait_for_msec(100);
-    APIC_TIMER_FREQ.init(apic::elapsed_count() * 10);
-    apic::stop_count();
+    let tsc_end = asmfunc::rdtsc();
+    TSC_FREQ.init((tsc_end - tsc_start) * 10);
 
-    apic::set_lvt_timer(TIMER_INT_VEC, false, true);
-    apic::set_divide_config(0);
-    apic::set_init_count(APIC_TIMER_FREQ.get() / TIMER_INT_FREQ);
+    MODE.init(if apic::supports_tsc_deadline() {
+        Mode::TscDeadline
+    } else {
+        Mode::Periodic
+    });
+    // Ticks raise it when a deadline of sleeping tasks has passed.
+    softirq::register(TIMER_INT_VEC, || TASK_MANAGER.wake_up_expired())?;
+    start();
 
     Ok(())
 }
 
+/// Starts timer interrupts on an application processor in the same [`Mode`] as the bootstrap
+/// processor. Call [`init()`] on the bootstrap processor first to measure the frequency, and
+/// [`cpu::init()`](crate::cpu::init) on the application processor.
+pub fn init_ap() {
+    start();
+}
+
+/// Starts timer interrupts on the current processor in [`MODE`].
+fn start() {
+    match MODE.get() {
+        Mode::TscDeadline => {
+            apic::set_lvt_timer(TIMER_INT_VEC, false, TimerMode::TscDeadline);
+            let next_tick = asmfunc::rdtsc() + tsc_per_tick();
+            this_cpu().timer().next_tick_tsc.store(next_tick, Relaxed);
+            apic::set_tsc_deadline(next_tick);
+        }
+        Mode::Periodic => {
+            // Measure before unmasking because measuring reprograms the timer.
+            let init_count = *APIC_TIMER_FREQ / TIMER_INT_FREQ;
+            apic::set_lvt_timer(TIMER_INT_VEC, false, TimerMode::Periodic);
+            apic::set_divide_config(0);
+            apic::set_init_count(init_count);
+        }
+    }
+}
+
 #[unsafe(no_mangle)]
 pub fn _int_handler_timer(prev_ctx: &Context) {
-    let current = COUNT.fetch_add(1, Relaxed) + 1;
-    PREV_INT_TSC.store(CURRENT_INT_TSC.load(Relaxed), Relaxed);
-    CURRENT_INT_TSC.store(asmfunc::rdtsc(), Relaxed);
+    let cpu = this_cpu();
+    let ticks = match MODE.get() {
+        Mode::Periodic => 1,
+        Mode::TscDeadline => rearm_deadline(cpu.timer()),
+    };
+    if ticks == 0 {
+        // Only a one-shot expiry has come, which is requested for timers of tasks.
+        apic::notify_end_of_interrupt();
+        softirq::raise(TIMER_INT_VEC);
+        return;
+    }
+
+    cpu.timer().ticks.fetch_add(ticks, Relaxed);
     apic::notify_end_of_interrupt();
-    if current.is_multiple_of((TIMER_INT_FREQ / TASK_SWITCH_FREQ) as _) {
-        // Safety: This is in interrupt handler and IF is not set.
-        unsafe { TASK_MANAGER.switch(prev_ctx) };
+    // Safety: This is in interrupt handler and IF is not set.
+    unsafe { TASK_MANAGER.tick(prev_ctx, ticks) };
+}
+
+/// Requests a timer interrupt on the current processor when TSC reaches `tsc` in addition to
+/// regular ticks. Returns `false` if the timer does not run in [`Mode::TscDeadline`], in which case
+/// the caller has to rely on ticks.
+///
+/// Each processor keeps only the earliest expiry requested, and forgets it once it fires. So a
+/// caller keeping more than one expiry has to request the next one after each fires, as the timer
+/// softirq does for timers of tasks. Otherwise the later ones wait for ticks.
+pub fn set_deadline(tsc: u64) -> bool {
+    if !MODE.is_initialized() || MODE.get() != Mode::TscDeadline {
+        return false;
+    }
+
+    // Prevent the timer interrupt handler from rearming the deadline between the comparison and
+    // writing the MSR.
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    let timer = this_cpu().timer();
+    let prev = timer.oneshot_tsc.fetch_min(tsc, Relaxed);
+    if tsc < prev && tsc < timer.next_tick_tsc.load(Relaxed) {
+        apic::set_tsc_deadline(tsc);
     }
+    if if_is_set {
+        asmfunc::sti();
+    }
+    true
+}
+
+/// Returns the TSC value at `instant`, rounded up so that it is not earlier. Call [`init()`]
+/// first.
+pub fn instant_to_tsc(instant: Instant) -> u64 {
+    let tsc = (instant.as_nanos() as u128 * TSC_FREQ.get() as u128).div_ceil(1_000_000_000);
+    BOOT_TSC
+        .get()
+        .saturating_add(u64::try_from(tsc).unwrap_or(u64::MAX))
+}
+
+/// Returns how many TSC counts a tick takes.
+fn tsc_per_tick() -> u64 {
+    TSC_FREQ.get() / TIMER_INT_FREQ as u64
+}
+
+/// Consumes expired one-shot expiries and ticks, then arms the earliest deadline. Returns how
+/// many ticks have elapsed since the last call.
+///
+/// Call it from the timer interrupt handler in [`Mode::TscDeadline`].
+fn rearm_deadline(timer: &TimerState) -> u64 {
+    let now = asmfunc::rdtsc();
+    let tsc_per_tick = tsc_per_tick();
+
+    let mut next_tick = timer.next_tick_tsc.load(Relaxed);
+    let ticks = if now >= next_tick {
+        // Count ticks missed while interrupts were disabled, too. Otherwise the tick count would
+        // fall behind the actual time.
+        let ticks = (now - next_tick) / tsc_per_tick + 1;
+        next_tick += ticks * tsc_per_tick;
+        timer.next_tick_tsc.store(next_tick, Relaxed);
+        ticks
+    } else {
+        0
+    };
+
+    let oneshot = timer.oneshot_tsc.load(Relaxed);
+    let oneshot = if oneshot <= now {
+        timer.oneshot_tsc.store(u64::MAX, Relaxed);
+        u64::MAX
+    } else {
+        oneshot
+    };
+
+    apic::set_tsc_deadline(next_tick.min(oneshot));
+    ticks
 }
 
+/// Returns nanoseconds since the timer started, or `0` before [`init()`] completes. TSC is
+/// assumed to be invariant and synchronized among processors, so it is comparable across them.
 pub fn get_timestamp() -> u64 {
     // NOTE: DO NOT use log crate in this function because logger mod depends on it.
 
-    const TIMER_INTERVAL_NANO: u64 = 1_000_000_000 / TIMER_INT_FREQ as u64;
+    if !TSC_FREQ.is_initialized() {
+        return 0;
+    }
+    let elapsed = asmfunc::rdtsc().saturating_sub(BOOT_TSC.get());
+    tsc_to_nanos(elapsed)
+}
 
-    let current_tsc = asmfunc::rdtsc();
-    let current_count = COUNT.load(Relaxed);
-    let current_int_tsc = CURRENT_INT_TSC.load(Relaxed);
-    let prev_int_tsc = PREV_INT_TSC.load(Relaxed);
+/// Converts `tsc` TSC counts to the time they take. Call [`init()`] first.
+pub fn tsc_to_duration(tsc: u64) -> Duration {
+    Duration::from_nanos(tsc_to_nanos(tsc))
+}
 
-    if prev_int_tsc == 0 {
-        current_count * TIMER_INTERVAL_NANO
-    } else {
-        current_count * TIMER_INTERVAL_NANO
-            + (current_tsc - current_int_tsc) * TIMER_INTERVAL_NANO
-                / (current_int_tsc - prev_int_tsc)
+fn tsc_to_nanos(tsc: u64) -> u64 {
+    (tsc as u128 * 1_000_000_000 / TSC_FREQ.get() as u128) as u64
+}
+
+/// A point in time, measured in nanoseconds since the timer started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Self {
+        Self(get_timestamp())
+    }
+
+    /// Returns nanoseconds since the timer started.
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the time elapsed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// Returns `self + duration`, or `None` if it overflows.
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// # Panics
+    ///
+    /// Panics if the result overflows. Use [`Instant::checked_add()`] to avoid it.
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.duration_since(rhs)
     }
 }
 
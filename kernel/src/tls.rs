@@ -0,0 +1,136 @@
+//! Provides thread-local storage of tasks.
+//!
+//! Each task owns a [`TlsBlock`] copied from the PT_TLS segment of the kernel, and FS base points
+//! to it while the task runs. The block follows the x86_64 layout: it ends right below the thread
+//! pointer held in FS base, and the first word at the thread pointer points to itself.
+//!
+//! GS base is not switched per task because it points to the per-CPU data.
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error};
+use core::{
+    arch::asm,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering::*},
+};
+
+use util::{asmfunc, bitfield::BitField as _, elf::TlsTemplate, sync::OnceStatic};
+
+/// MSR holding FS base.
+pub const IA32_FS_BASE: u32 = 0xc000_0100;
+
+/// Size of the thread control block placed at the thread pointer, which holds only the pointer to
+/// itself.
+const TCB_SIZE: usize = 8;
+
+/// Whether FS base is accessed with RDFSBASE and WRFSBASE instead of the MSR. Read by
+/// `_switch_context` and `_restore_context`.
+pub static USE_FSGSBASE: AtomicBool = AtomicBool::new(false);
+
+/// Template of thread-local storage of the kernel.
+static TEMPLATE: OnceStatic<TlsTemplate> = OnceStatic::new();
+
+/// Records `template` passed by the loader and enables FSGSBASE instructions on the bootstrap
+/// processor. Call this before [`TaskManager::init()`](crate::task::TaskManager::init).
+pub fn init(template: TlsTemplate) {
+    TEMPLATE.init(template);
+    init_ap();
+}
+
+/// Enables FSGSBASE instructions on the current processor if supported. Call [`init()`] on the
+/// bootstrap processor first.
+pub fn init_ap() {
+    let (_, ebx, _, _) = asmfunc::cpuid_count(7, 0);
+    let fsgsbase = ebx.get_bit(0);
+    let mut cr4 = asmfunc::get_cr4();
+    cr4.set_bit(16, fsgsbase); // FSGSBASE: Enable RDFSBASE, WRFSBASE, RDGSBASE and WRGSBASE.
+    asmfunc::set_cr4(cr4);
+    USE_FSGSBASE.store(fsgsbase, Relaxed);
+}
+
+/// Returns FS base of the current processor.
+pub fn fs_base() -> u64 {
+    if USE_FSGSBASE.load(Relaxed) {
+        let base: u64;
+        unsafe { asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) };
+        base
+    } else {
+        asmfunc::rdmsr(IA32_FS_BASE)
+    }
+}
+
+/// Sets FS base of the current processor to `base`.
+///
+/// # Safety
+///
+/// Thread-local variables are accessed through FS base, so `base` must be the thread pointer of
+/// a [`TlsBlock`] living while it is set.
+pub unsafe fn set_fs_base(base: u64) {
+    if USE_FSGSBASE.load(Relaxed) {
+        unsafe { asm!("wrfsbase {}", in(reg) base, options(nostack, preserves_flags)) };
+    } else {
+        asmfunc::wrmsr(IA32_FS_BASE, base);
+    }
+}
+
+/// Thread-local storage of a task.
+#[derive(Debug)]
+pub struct TlsBlock {
+    ptr: NonNull<u8>,
+}
+
+// Safety: `TlsBlock` owns the memory like `Box`.
+unsafe impl Send for TlsBlock {}
+unsafe impl Sync for TlsBlock {}
+
+impl TlsBlock {
+    /// Allocates a block initialized from the template. Call [`init()`] first.
+    pub fn new() -> Self {
+        let template = TEMPLATE.get();
+        let layout = Self::layout();
+        // Safety: `layout` is not zero-sized.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            handle_alloc_error(layout);
+        };
+        let block = Self { ptr };
+
+        let tp = block.thread_pointer();
+        let start = tp - template.tp_offset();
+        // Safety: The block has room for the image below the thread pointer and for the TCB. The
+        //   image is in a loaded segment of the kernel, and the zero-filled rest is left as is.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                template.addr as *const u8,
+                start as *mut u8,
+                template.file_size as usize,
+            );
+            // The thread pointer is aligned only to the template.
+            (tp as *mut u64).write_unaligned(tp);
+        }
+        block
+    }
+
+    /// Returns the thread pointer to set to FS base.
+    pub fn thread_pointer(&self) -> u64 {
+        self.ptr.as_ptr() as u64 + TEMPLATE.get().tp_offset()
+    }
+
+    fn layout() -> Layout {
+        let template = TEMPLATE.get();
+        let align = (template.align as usize).max(align_of::<u64>());
+        Layout::from_size_align(template.tp_offset() as usize + TCB_SIZE, align).unwrap()
+    }
+}
+
+impl Default for TlsBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        // Safety: `self.ptr` is allocated with the same layout in `new()`.
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout()) };
+    }
+}
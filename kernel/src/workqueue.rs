@@ -0,0 +1,134 @@
+//! Runs deferred work in dedicated worker tasks.
+//!
+//! Unlike softirqs, work runs in task context, so it may sleep and take long. Interrupt handlers
+//! can queue work because [`WorkQueue::queue()`] never sleeps.
+//!
+//! ```ignore
+//! workqueue::schedule(move || driver.process_packets());
+//! ```
+
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, sync::Arc};
+use core::fmt;
+
+use log::warn;
+use util::{
+    error,
+    error::Result,
+    sync::{InterruptFreeMutex, OnceStatic},
+};
+
+use crate::{
+    smp::CPUS,
+    sync::{WaitQueue, wait_until},
+    task::Builder,
+};
+
+/// Work run by a worker task.
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Work queue shared by the whole kernel, which has as many workers as processors. Workers are not
+/// bound to processors, so work may run on any of them.
+pub static SYSTEM: OnceStatic<Arc<WorkQueue>> = OnceStatic::new();
+
+/// Creates [`SYSTEM`]. Call this after [`smp::init()`](crate::smp::init) to know the number of
+/// processors.
+pub fn init() -> Result<()> {
+    SYSTEM.init(WorkQueue::new("events", CPUS.len())?);
+    Ok(())
+}
+
+/// Queues `work` to [`SYSTEM`]. See [`WorkQueue::queue()`].
+pub fn schedule(work: impl FnOnce() + Send + 'static) {
+    SYSTEM.queue(work);
+}
+
+/// Queue of work serviced by worker tasks in FIFO order.
+#[derive(Debug)]
+pub struct WorkQueue {
+    name: String,
+    state: InterruptFreeMutex<State>,
+}
+
+/// State of [`WorkQueue`] shared with interrupt handlers.
+struct State {
+    queue: VecDeque<Work>,
+    /// Workers sleeping until work is queued.
+    idle: WaitQueue,
+    /// Whether [`WorkQueue::shutdown()`] has been called.
+    closed: bool,
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("queued", &self.queue.len())
+            .field("idle", &self.idle)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl WorkQueue {
+    /// Creates a queue named `name` serviced by `workers` worker tasks, which are named
+    /// `name/index`.
+    pub fn new(name: &str, workers: usize) -> Result<Arc<Self>> {
+        if workers == 0 {
+            error!(format!("work queue {} needs a worker", name));
+        }
+        let wq = Arc::new(Self {
+            name: name.into(),
+            state: InterruptFreeMutex::new(State {
+                queue: VecDeque::new(),
+                idle: WaitQueue::new(),
+                closed: false,
+            }),
+        });
+        for i in 0..workers {
+            let worker = Arc::clone(&wq);
+            Builder::new()
+                .name(format!("{}/{}", name, i))
+                .spawn(move || worker.run())?;
+        }
+        Ok(wq)
+    }
+
+    /// Returns the name of the queue.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queues `work` to run in a worker task. Can be called from interrupt handlers. Work queued
+    /// after [`WorkQueue::shutdown()`] is dropped without running.
+    pub fn queue(&self, work: impl FnOnce() + Send + 'static) {
+        let mut state = self.state.lock();
+        if state.closed {
+            warn!("work queued to {} after shutdown", self.name);
+            return;
+        }
+        state.queue.push_back(Box::new(work));
+        state.idle.wake_one();
+    }
+
+    /// Lets workers exit after running all queued work.
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        state.idle.wake_all();
+    }
+
+    /// Main loop of worker tasks.
+    fn run(&self) {
+        // `None` once the queue is shut down and empty.
+        while let Some(work) = wait_until(
+            &self.state,
+            |state| &mut state.idle,
+            |state| match state.queue.pop_front() {
+                Some(work) => Some(Some(work)),
+                None if state.closed => Some(None),
+                None => None,
+            },
+        ) {
+            work();
+        }
+    }
+}
@@ -30,7 +30,9 @@ use uefi::{
 };
 use util::{
     asmfunc,
-    elf::{Elf64Ehdr, Elf64Phdr, ElfProgType},
+    elf::{
+        Elf64Ehdr, Elf64Phdr, Elf64Shdr, ElfProgType, ElfSectionType, SymbolTableInfo, TlsTemplate,
+    },
     paging::{PAGE_SIZE, PageEntry, PageTable, VirtualAddress},
     screen::{FrameBufferInfo, PixelFormat},
 };
@@ -186,6 +188,11 @@ unsafe fn actual_main(image: Handle, st: SystemTable<Boot>) -> Result<(), MyErro
         kernel_phys_head + end - start
     );
 
+    // Keep the symbol table for the kernel to symbolize addresses.
+    let symbols = load_symbols(&st, tmp_addr, elf_header)?;
+    // The initialization image is in a loaded segment, so the kernel reads it at its address.
+    let tls = TlsTemplate::from_phdrs(elf_phdrs);
+
     // Get frame buffer info.
     // We need to get handle for taking GraphicsOutput.
     let mut graphics_handles = [MaybeUninit::uninit(); 64];
@@ -226,9 +233,67 @@ unsafe fn actual_main(image: Handle, st: SystemTable<Boot>) -> Result<(), MyErro
     // Set new PML4.
     asmfunc::set_cr3(new_pml4 as *const _ as _);
 
-    type EntryFn = extern "sysv64" fn(&FrameBufferInfo, &mut MemoryMap, SystemTable<Runtime>) -> !;
+    type EntryFn = extern "sysv64" fn(
+        &FrameBufferInfo,
+        &mut MemoryMap,
+        SystemTable<Runtime>,
+        &SymbolTableInfo,
+        &TlsTemplate,
+    ) -> !;
     let kernel_entry: EntryFn = transmute(elf_header.entry);
-    kernel_entry(&fb_info, &mut memmap, runtime_services);
+    kernel_entry(&fb_info, &mut memmap, runtime_services, &symbols, &tls);
+}
+
+/// Copies the symbol table of the kernel file at `file` and its string table to `LOADER_DATA`
+/// pages, which the kernel does not overwrite. If the kernel has no symbol table, returns
+/// [`SymbolTableInfo`] whose addresses are `0`.
+unsafe fn load_symbols(
+    st: &SystemTable<Boot>,
+    file: u64,
+    elf_header: &Elf64Ehdr,
+) -> Result<SymbolTableInfo, MyError> {
+    if elf_header.shoff == 0 {
+        return Ok(SymbolTableInfo::default());
+    }
+    let shdrs = slice::from_raw_parts(
+        (file + elf_header.shoff) as *const Elf64Shdr,
+        elf_header.shnum as _,
+    );
+    let Some(symtab) = shdrs.iter().find(|shdr| shdr.ty == ElfSectionType::SYMTAB) else {
+        println!("kernel has no symbol table");
+        return Ok(SymbolTableInfo::default());
+    };
+    let Some(strtab) = shdrs.get(symtab.link as usize) else {
+        return Ok(SymbolTableInfo::default());
+    };
+
+    let num_pages = (symtab.size + strtab.size).div_ceil(PAGE_SIZE as _);
+    let addr = st
+        .boot_services()
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            num_pages as _,
+        )
+        .map_err(|e| error!(e))?;
+    // The symbol table is put first to keep its alignment.
+    ptr::copy_nonoverlapping(
+        (file + symtab.offset) as *const u8,
+        addr as *mut u8,
+        symtab.size as _,
+    );
+    ptr::copy_nonoverlapping(
+        (file + strtab.offset) as *const u8,
+        (addr + symtab.size) as *mut u8,
+        strtab.size as _,
+    );
+
+    Ok(SymbolTableInfo {
+        symtab: addr,
+        symtab_size: symtab.size,
+        strtab: addr + symtab.size,
+        strtab_size: strtab.size,
+    })
 }
 
 /// Get protocol `P` from boot servieces.
@@ -1,14 +1,108 @@
 //! Provides Local APIC utilities.
 
-use crate::{bitfield::BitField, paging::ADDRESS_CONVERTER};
+use core::{
+    hint,
+    sync::atomic::{self, Ordering::SeqCst},
+};
 
+use crate::{asmfunc, bitfield::BitField, paging::ADDRESS_CONVERTER};
+
+const ID_ADDR: u64 = 0xfee0_0020;
 const EOI_ADDR: u64 = 0xfee0_00b0;
+const SPURIOUS_VECTOR_ADDR: u64 = 0xfee0_00f0;
+const ICR_LOW_ADDR: u64 = 0xfee0_0300;
+const ICR_HIGH_ADDR: u64 = 0xfee0_0310;
 
 const LVT_TIMER_ADDR: u64 = 0xfee0_0320;
 const INIT_COUNT_ADDR: u64 = 0xfee0_0380;
 const CURRENT_COUNT_ADDR: u64 = 0xfee0_0390;
 const DIVIDE_CONFIG_ADDR: u64 = 0xfee0_03e0;
 
+/// MSR holding the base address and the state of Local APIC.
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// MSR holding the TSC value at which the timer fires in [TSC-deadline mode][TimerMode].
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Represents the timer modes selected by LVT timer register.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Counts down once from the initial count register.
+    OneShot = 0b00,
+    /// Reloads the initial count register after counting down, then counts down again.
+    Periodic = 0b01,
+    /// Fires when TSC reaches the value written to `IA32_TSC_DEADLINE` MSR. The initial count
+    /// register is ignored in this mode.
+    TscDeadline = 0b10,
+}
+
+/// Represents how an inter-processor interrupt is delivered, selected by the interrupt command
+/// register.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Delivers the interrupt of the vector.
+    Fixed = 0b000,
+    /// Delivers an NMI. The vector is ignored.
+    Nmi = 0b100,
+    /// Resets the processor to the wait-for-SIPI state. The vector is ignored.
+    Init = 0b101,
+    /// Starts the processor in the wait-for-SIPI state in real mode at `vector << 12`.
+    StartUp = 0b110,
+}
+
+/// Returns the Local APIC ID of the current processor.
+pub fn local_apic_id() -> u8 {
+    // Safety: `ID_ADDR` is valid because this is CPU-defined and properly aligned.
+    let id: u32 = unsafe { ADDRESS_CONVERTER.as_ref().read_volatile(ID_ADDR) }.unwrap();
+    id.get_bits(24..) as u8
+}
+
+/// Returns whether the current processor is the bootstrap processor.
+pub fn is_bsp() -> bool {
+    asmfunc::rdmsr(IA32_APIC_BASE).get_bit(8)
+}
+
+/// Enables the Local APIC of the current processor by software, delivering spurious interrupts to
+/// `spurious_vector`. Local APICs of application processors are disabled after INIT.
+pub fn enable(spurious_vector: u8) {
+    // Safety: `SPURIOUS_VECTOR_ADDR` is valid because this is CPU-defined and properly aligned.
+    unsafe {
+        ADDRESS_CONVERTER
+            .as_ref()
+            .write_volatile(SPURIOUS_VECTOR_ADDR, 1 << 8 | spurious_vector as u32)
+    };
+}
+
+/// Sends an inter-processor interrupt to the processor whose Local APIC ID is `apic_id`, and
+/// waits until it is delivered.
+pub fn send_ipi(apic_id: u8, mode: DeliveryMode, vector: u8) {
+    // INIT has to assert the level. Others ignore it.
+    let level = (mode == DeliveryMode::Init) as u32;
+    // Safety: `ICR_HIGH_ADDR` and `ICR_LOW_ADDR` are valid because they are CPU-defined and
+    //         properly aligned. Writing the low half sends the interrupt, so write it last.
+    unsafe {
+        let converter = ADDRESS_CONVERTER.as_ref();
+        converter.write_volatile(ICR_HIGH_ADDR, (apic_id as u32) << 24);
+        converter.write_volatile(
+            ICR_LOW_ADDR,
+            level << 14 | (mode as u32) << 8 | vector as u32,
+        );
+    }
+    // Wait while the delivery status is pending.
+    while unsafe {
+        ADDRESS_CONVERTER
+            .as_ref()
+            .read_volatile::<u32>(ICR_LOW_ADDR)
+    }
+    .unwrap()
+    .get_bit(12)
+    {
+        hint::spin_loop();
+    }
+}
+
 /// Notify end of interrupt to Local APIC.
 pub fn notify_end_of_interrupt() {
     unsafe { ADDRESS_CONVERTER.as_ref().write_volatile(EOI_ADDR, 1) };
@@ -20,17 +114,35 @@ pub fn notify_end_of_interrupt() {
 ///
 /// * `vector` - Interrupt vector number.
 /// * `mask` - If `true`, inhibits reception of the interrupt.
-/// * `periodic` - If `true`, reloads initial count registers, then count down again.
-//
-// TODO: Support TSC-Deadline mode.
-pub fn set_lvt_timer(vector: u8, mask: bool, periodic: bool) {
+/// * `mode` - Timer mode. Check [`supports_tsc_deadline()`] before selecting
+///   [`TimerMode::TscDeadline`].
+pub fn set_lvt_timer(vector: u8, mask: bool, mode: TimerMode) {
     // Safety: `LVT_TIMER_ADDR` is valid because this is CPU-defined and properly aligned.
     unsafe {
         ADDRESS_CONVERTER.as_ref().write_volatile(
             LVT_TIMER_ADDR,
-            (periodic as u32) << 17 | (mask as u32) << 16 | vector as u32,
+            (mode as u32) << 17 | (mask as u32) << 16 | vector as u32,
         )
     };
+    if mode == TimerMode::TscDeadline {
+        // Writes to `IA32_TSC_DEADLINE` are not serialized with the MMIO write above, so they may
+        // be ignored unless the mode switch is completed before them.
+        atomic::fence(SeqCst);
+    }
+}
+
+/// Returns whether the Local APIC timer supports [`TimerMode::TscDeadline`].
+pub fn supports_tsc_deadline() -> bool {
+    let (_, _, ecx, _) = asmfunc::cpuid(1);
+    ecx.get_bit(24)
+}
+
+/// Arms the timer so that it fires when TSC reaches `tsc`. Passing `0` disarms it.
+///
+/// This has effect only when the timer is in [`TimerMode::TscDeadline`]. If `tsc` is already
+/// passed, the interrupt occurs immediately.
+pub fn set_tsc_deadline(tsc: u64) {
+    asmfunc::wrmsr(IA32_TSC_DEADLINE, tsc);
 }
 
 /// Sets `value` to initial count register
@@ -78,7 +190,7 @@ pub fn set_divide_config(order: u8) {
 /// Starts oneshot counting timer with disabling timer interrupt. Set divide config as you want
 /// before calling.
 pub fn start_count() {
-    set_lvt_timer(0, true, false);
+    set_lvt_timer(0, true, TimerMode::OneShot);
     set_init_count(u32::MAX);
 }
 
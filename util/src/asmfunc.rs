@@ -19,6 +19,25 @@ pub fn hlt() {
     unsafe { asm!("hlt") };
 }
 
+/// Returns the current CR0 value.
+pub fn get_cr0() -> u64 {
+    let cr0;
+    unsafe { asm!("mov {}, cr0", out(reg) cr0) };
+    cr0
+}
+
+/// Sets CR0 to `cr0`.
+pub fn set_cr0(cr0: u64) {
+    unsafe { asm!("mov cr0, {}", in(reg) cr0) };
+}
+
+/// Returns the current CR2 value, the address that caused the last page fault.
+pub fn get_cr2() -> u64 {
+    let cr2;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+    cr2
+}
+
 /// Returns the current CR3 value.
 pub fn get_cr3() -> u64 {
     let cr3;
@@ -31,6 +50,43 @@ pub fn set_cr3(cr3: u64) {
     unsafe { asm!("mov cr3, {}", in(reg) cr3) };
 }
 
+/// Returns the current CR4 value.
+pub fn get_cr4() -> u64 {
+    let cr4;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4) };
+    cr4
+}
+
+/// Sets CR4 to `cr4`.
+pub fn set_cr4(cr4: u64) {
+    unsafe { asm!("mov cr4, {}", in(reg) cr4) };
+}
+
+/// Sets the extended control register `xcr` to `value`. CR4.OSXSAVE must be set.
+pub fn xsetbv(xcr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") xcr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+        )
+    };
+}
+
+/// Returns the current RBP value, that is the frame pointer of the caller.
+#[inline(always)]
+pub fn get_rbp() -> u64 {
+    let rbp;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    rbp
+}
+
+/// Invalidates TLB entries for the page containing `addr`.
+pub fn invlpg(addr: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr) };
+}
+
 /// Set all data segment registers (DS, ES, FS, GS) to `segment`.
 pub fn set_ds_all(segment: u16) {
     unsafe {
@@ -104,6 +160,27 @@ pub fn cpuid(input: u32) -> (u32, u32, u32, u32) {
     (eax, ebx, ecx, edx)
 }
 
+/// Get CPUID value with initial EAX `input` and ECX `subleaf`.
+pub fn cpuid_count(input: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let eax;
+    let ebx: u64;
+    let ecx;
+    let edx;
+    // RBX is reserved by LLVM, so restore it after CPUID.
+    unsafe {
+        asm!(
+            "mov {b:r}, rbx",
+            "cpuid",
+            "xchg {b:r}, rbx",
+            inout("eax") input => eax,
+            b = out(reg) ebx,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+        )
+    };
+    (eax, ebx as u32, ecx, edx)
+}
+
 /// Returs whether IF (interrupt flag) is set.
 pub fn get_if() -> bool {
     let flags: u64;
@@ -166,6 +243,33 @@ pub fn io_outb(port: u16, value: u8) {
     };
 }
 
+/// Reads the model specific register `msr`.
+pub fn rdmsr(msr: u32) -> u64 {
+    let eax: u32;
+    let edx: u32;
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") eax,
+            out("edx") edx,
+        )
+    };
+    eax as u64 | (edx as u64) << 32
+}
+
+/// Writes `value` to the model specific register `msr`.
+pub fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+        )
+    };
+}
+
 /// Set CS and SS to `cs` and `ss`, respectively.
 pub fn set_cs_ss(cs: u16, ss: u16) {
     unsafe { _set_cs_ss(cs, ss) };
@@ -177,6 +177,19 @@ impl<K: Hash + Eq, V> HashMap<K, V> {
         unreachable!()
     }
 
+    /// Returns an iterator over all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets.iter().filter_map(|bucket| match bucket {
+            Bucket::Some(entry) => Some((&entry.key, &entry.value)),
+            _ => None,
+        })
+    }
+
+    /// Returns an iterator over all values in arbitrary order.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
     /// Capacity of [`HashMpa`].
     pub fn capacity(&self) -> usize {
         self.buckets.len()
@@ -131,3 +131,250 @@ impl ElfProgFlags {
         self.0.get_bit(Self::READ_BIT)
     }
 }
+
+/// Represents a 64-bit ELF section header.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Elf64Shdr {
+    /// Section name, an offset in the section header string table.
+    pub name: u32,
+    /// Identifies the type of the section.
+    pub ty: ElfSectionType,
+    /// Section attributes.
+    pub flags: u64,
+    /// Section virtual address at execution, or 0.
+    pub addr: u64,
+    /// Section file offset.
+    pub offset: u64,
+    /// Section size in bytes.
+    pub size: u64,
+    /// Index of an associated section. For a symbol table, the index of its string table.
+    pub link: u32,
+    /// Extra information depending on the section type.
+    pub info: u32,
+    /// Section alignment.
+    pub addralign: u64,
+    /// Size of each entry if the section holds a table.
+    pub entsize: u64,
+}
+
+/// Represents a section type in [Elf64Shdr].
+///
+/// This is not an enum because there are many OS- and processor-specific types, which may appear
+/// in any executable.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfSectionType(pub u32);
+
+impl ElfSectionType {
+    /// Section header table entry unused.
+    pub const NULL: Self = Self(0);
+    /// Program data.
+    pub const PROGBITS: Self = Self(1);
+    /// Symbol table.
+    pub const SYMTAB: Self = Self(2);
+    /// String table.
+    pub const STRTAB: Self = Self(3);
+    /// Relocation entries with addends.
+    pub const RELA: Self = Self(4);
+    /// Symbol hash table.
+    pub const HASH: Self = Self(5);
+    /// Dynamic linking information.
+    pub const DYNAMIC: Self = Self(6);
+    /// Notes.
+    pub const NOTE: Self = Self(7);
+    /// Program space with no data (bss).
+    pub const NOBITS: Self = Self(8);
+    /// Relocation entries without addends.
+    pub const REL: Self = Self(9);
+    /// Dynamic linker symbol table.
+    pub const DYNSYM: Self = Self(11);
+}
+
+/// Represents a 64-bit ELF symbol table entry.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Elf64Sym {
+    /// Symbol name, an offset in the string table.
+    pub name: u32,
+    /// Symbol type (3:0) and binding (7:4).
+    pub info: u8,
+    /// Symbol visibility.
+    pub other: u8,
+    /// Index of the section the symbol is defined in. 0 if undefined.
+    pub shndx: u16,
+    /// Symbol value, the address for functions and objects.
+    pub value: u64,
+    /// Symbol size in bytes, or 0 if unknown.
+    pub size: u64,
+}
+
+impl Elf64Sym {
+    /// Returns the type of the symbol.
+    pub fn ty(&self) -> ElfSymType {
+        ElfSymType(self.info.get_bits(..4))
+    }
+
+    /// Returns the binding of the symbol.
+    pub fn binding(&self) -> ElfSymBinding {
+        ElfSymBinding(self.info.get_bits(4..))
+    }
+
+    /// Returns whether the symbol is defined in some section.
+    pub fn is_defined(&self) -> bool {
+        self.shndx != 0
+    }
+}
+
+/// Represents a symbol type in [Elf64Sym].
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfSymType(pub u8);
+
+impl ElfSymType {
+    /// Type is not specified, e.g. labels in assembly.
+    pub const NOTYPE: Self = Self(0);
+    /// Data object.
+    pub const OBJECT: Self = Self(1);
+    /// Function.
+    pub const FUNC: Self = Self(2);
+    /// Section.
+    pub const SECTION: Self = Self(3);
+    /// Source file name.
+    pub const FILE: Self = Self(4);
+    /// Thread-local storage object.
+    pub const TLS: Self = Self(6);
+}
+
+/// Represents a symbol binding in [Elf64Sym].
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfSymBinding(pub u8);
+
+impl ElfSymBinding {
+    /// Not visible outside the object file.
+    pub const LOCAL: Self = Self(0);
+    /// Visible to all object files.
+    pub const GLOBAL: Self = Self(1);
+    /// Global with lower precedence.
+    pub const WEAK: Self = Self(2);
+}
+
+/// Returns the NUL-terminated string starting at `offset` in the string table `strtab`. Returns
+/// `None` if it is out of the table, not terminated or not UTF-8.
+pub fn get_str(strtab: &[u8], offset: u32) -> Option<&str> {
+    let s = strtab.get(offset as usize..)?;
+    let len = s.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&s[..len]).ok()
+}
+
+/// Template of thread-local storage described by the PT_TLS segment, passed from the loader to
+/// the kernel. Each thread gets a TLS block initialized by copying it. Sizes are `0` if there is no
+/// such segment.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlsTemplate {
+    /// Virtual address of the initialization image.
+    pub addr: u64,
+    /// Size of the initialization image in bytes. The rest of the block is zero-filled.
+    pub file_size: u64,
+    /// Size of the TLS block in bytes.
+    pub mem_size: u64,
+    /// Alignment of the TLS block.
+    pub align: u64,
+}
+
+impl TlsTemplate {
+    /// Returns the template described by the PT_TLS segment in `phdrs`, or an empty one if there
+    /// is no such segment.
+    pub fn from_phdrs(phdrs: &[Elf64Phdr]) -> Self {
+        phdrs
+            .iter()
+            .find(|phdr| phdr.ty == ElfProgType::Tls)
+            .map_or_else(Self::default, |phdr| Self {
+                addr: phdr.vaddr,
+                file_size: phdr.filesz,
+                mem_size: phdr.memsz,
+                align: phdr.align,
+            })
+    }
+
+    /// Returns the distance from the start of the TLS block to the thread pointer in the x86_64
+    /// layout, where the block ends right below the thread pointer. The thread pointer has to be
+    /// aligned to [`TlsTemplate::align`] so that the block is, too.
+    pub fn tp_offset(&self) -> u64 {
+        self.mem_size.next_multiple_of(self.align.max(1))
+    }
+}
+
+/// Location of a symbol table and its string table in memory, passed from the loader to the
+/// kernel. Addresses are `0` if there is no symbol table.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SymbolTableInfo {
+    /// Address of the symbol table.
+    pub symtab: u64,
+    /// Size of the symbol table in bytes.
+    pub symtab_size: u64,
+    /// Address of the string table.
+    pub strtab: u64,
+    /// Size of the string table in bytes.
+    pub strtab_size: u64,
+}
+
+/// Provides lookups of symbols in a symbol table.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symbols: &'a [Elf64Sym],
+    strtab: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Constructs [SymbolTable] from `symbols` and the string table `strtab` for their names.
+    pub fn new(symbols: &'a [Elf64Sym], strtab: &'a [u8]) -> Self {
+        Self { symbols, strtab }
+    }
+
+    /// Returns all symbols.
+    pub fn symbols(&self) -> &'a [Elf64Sym] {
+        self.symbols
+    }
+
+    /// Returns the name of `sym`.
+    pub fn name(&self, sym: &Elf64Sym) -> Option<&'a str> {
+        get_str(self.strtab, sym.name)
+    }
+
+    /// Returns the name of the code symbol containing `addr` and the offset of `addr` from it.
+    ///
+    /// Functions whose ranges contain `addr` are preferred. Otherwise, returns the nearest
+    /// preceding symbol without size, such as a label in assembly, which is taken to extend up to
+    /// the next symbol in the same section.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.symbols
+            .iter()
+            .filter(|sym| {
+                sym.is_defined()
+                    && (sym.ty() == ElfSymType::FUNC || sym.ty() == ElfSymType::NOTYPE)
+                    && sym.value <= addr
+                    && self.contains(sym, addr)
+            })
+            .filter_map(|sym| Some((self.name(sym).filter(|name| !name.is_empty())?, sym)))
+            .max_by_key(|(_, sym)| (sym.size != 0, sym.value))
+            .map(|(name, sym)| (name, addr - sym.value))
+    }
+
+    /// Returns whether `addr`, which is not below `sym`, is in the range of `sym`.
+    fn contains(&self, sym: &Elf64Sym, addr: u64) -> bool {
+        if sym.size != 0 {
+            return addr - sym.value < sym.size;
+        }
+        // No other symbol of the same section starts between the symbol and `addr`.
+        !self.symbols.iter().any(|other| {
+            other.is_defined()
+                && other.shndx == sym.shndx
+                && sym.value < other.value
+                && other.value <= addr
+        })
+    }
+}
@@ -1,5 +1,9 @@
 //! Provides interrupt utilities.
 
+use core::fmt::{Debug, Display};
+
+use crate::bitfield::BitField as _;
+
 /// Information where the interrupt occurs, passed by a processor.
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -15,3 +19,125 @@ pub struct InterruptFrame {
     /// SS.
     pub ss: u64,
 }
+
+/// Error code passed by a processor on page faults.
+///
+/// | Bit | Description |
+/// | :---: | :--- |
+/// | 0 (P) | If 0, the page was not present. Otherwise, the access violated page protection. |
+/// | 1 (W/R) | If 1, the access was a write. Otherwise, a read. |
+/// | 2 (U/S) | If 1, the access was in user mode. |
+/// | 3 (RSVD) | If 1, a reserved bit was set in some paging-structure entry. |
+/// | 4 (I/D) | If 1, the access was an instruction fetch. |
+/// | 5 (PK) | If 1, the access violated protection keys. |
+/// | 6 (SS) | If 1, the access was a shadow-stack access. |
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultErrorCode(u64);
+
+impl PageFaultErrorCode {
+    /// Constructs [`PageFaultErrorCode`] from the raw error code.
+    pub const fn new(code: u64) -> Self {
+        Self(code)
+    }
+
+    /// Returns the raw error code.
+    pub const fn raw(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether the fault was caused by a page-level protection violation. If `false`,
+    /// caused by a non-present page.
+    pub fn present(&self) -> bool {
+        self.0.get_bit(0)
+    }
+
+    /// Returns whether the access causing the fault was a write.
+    pub fn write(&self) -> bool {
+        self.0.get_bit(1)
+    }
+
+    /// Returns whether the access causing the fault was in user mode.
+    pub fn user(&self) -> bool {
+        self.0.get_bit(2)
+    }
+
+    /// Returns whether the fault was caused by a reserved bit set in some paging-structure entry.
+    pub fn reserved(&self) -> bool {
+        self.0.get_bit(3)
+    }
+
+    /// Returns whether the access causing the fault was an instruction fetch.
+    pub fn instruction_fetch(&self) -> bool {
+        self.0.get_bit(4)
+    }
+
+    /// Returns whether the fault was caused by protection keys.
+    pub fn protection_key(&self) -> bool {
+        self.0.get_bit(5)
+    }
+
+    /// Returns whether the access causing the fault was a shadow-stack access.
+    pub fn shadow_stack(&self) -> bool {
+        self.0.get_bit(6)
+    }
+}
+
+impl From<u64> for PageFaultErrorCode {
+    fn from(value: u64) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Debug for PageFaultErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PageFaultErrorCode")
+            .field("present", &self.present())
+            .field("write", &self.write())
+            .field("user", &self.user())
+            .field("reserved", &self.reserved())
+            .field("instruction_fetch", &self.instruction_fetch())
+            .field("protection_key", &self.protection_key())
+            .field("shadow_stack", &self.shadow_stack())
+            .finish()
+    }
+}
+
+impl Display for PageFaultErrorCode {
+    /// Formats like `"supervisor write to non-present page"` followed by the other flags, for
+    /// example `" (RSVD, PK)"`.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mode = if self.user() { "user" } else { "supervisor" };
+        let access = if self.instruction_fetch() {
+            "instruction fetch from"
+        } else if self.write() {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.present() {
+            "protected page"
+        } else {
+            "non-present page"
+        };
+        write!(f, "{} {} {}", mode, access, page)?;
+
+        let mut first = true;
+        for (set, name) in [
+            (self.reserved(), "RSVD"),
+            (self.protection_key(), "PK"),
+            (self.shadow_stack(), "SS"),
+        ] {
+            if !set {
+                continue;
+            }
+            f.write_str(if first { " (" } else { ", " })?;
+            f.write_str(name)?;
+            first = false;
+        }
+        if !first {
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
@@ -24,6 +24,7 @@ pub mod interrupt;
 pub mod paging;
 pub mod pci;
 pub mod screen;
+pub mod serial;
 pub mod sync;
 
 #[cfg(feature = "alloc")]
@@ -35,4 +36,7 @@ pub mod error;
 #[cfg(feature = "alloc")]
 pub mod hash;
 
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+
 pub use macros::*;
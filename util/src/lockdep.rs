@@ -0,0 +1,475 @@
+//! Validates the order of taking locks to find potential deadlocks before they happen.
+//!
+//! Locks created at the same place of the source share a [`LockClass`]. Each time a lock is taken
+//! while others are held, the order from the classes of the held ones to its class is recorded in
+//! a graph. When an order closes a cycle in the graph, some processors may deadlock by taking the
+//! locks in different orders, so the cycle is reported with the places where each order was first
+//! seen. Each order is checked only once, so a cycle is reported only the first time it appears.
+//!
+//! Held locks are recorded in a [`HeldLocks`], which belongs to the processor for locks held with
+//! interrupts disabled, or to the task for locks held while sleeping. Locks of the same class held
+//! at once, like run queues of two processors, are not checked against each other.
+//!
+//! Tables have fixed sizes so that the validator never allocates memory, which takes locks. It
+//! stops validating once a table is full. For the same reason, reports are written to
+//! [`SerialWriter`] rather than the logger.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt::Write,
+    hint,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering::*},
+};
+
+use crate::{asmfunc, serial::SerialWriter, sync::OnceStatic};
+
+/// The maximum number of lock classes.
+const MAX_CLASSES: usize = 256;
+
+/// The maximum number of recorded orders.
+const MAX_ORDERS: usize = 1024;
+
+/// The maximum number of locks held at once by a [`HeldLocks`].
+const MAX_HELD: usize = 32;
+
+/// The maximum number of orders logged for a cycle.
+const MAX_REPORTED: usize = 8;
+
+/// Marks classes not reached in [`OrderGraph::find_path()`].
+const UNREACHED: u16 = u16::MAX;
+
+/// Bitmap of classes taken after each class. The bit `to` of `ORDERS[from]` is set if `to` has
+/// been taken while holding `from`. Read without [`GRAPH_LOCK`].
+static ORDERS: [[AtomicU64; MAX_CLASSES / 64]; MAX_CLASSES] =
+    [const { [const { AtomicU64::new(0) }; MAX_CLASSES / 64] }; MAX_CLASSES];
+
+/// Lock of [`GRAPH`]. This is a plain spinlock because validating it would recurse.
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+
+static GRAPH: GraphCell = GraphCell(UnsafeCell::new(OrderGraph::new()));
+
+/// Whether validation goes on. Cleared when a table is full.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Returns the locks held by the current processor, registered by [`set_cpu_locks()`].
+static CPU_LOCKS: OnceStatic<fn() -> Option<&'static HeldLocks>> = OnceStatic::new();
+
+/// Registers `f` returning the [`HeldLocks`] of the current processor, or `None` if it is not
+/// available yet. Locks taken with interrupts disabled are validated only after this is called.
+pub fn set_cpu_locks(f: fn() -> Option<&'static HeldLocks>) {
+    CPU_LOCKS.init(f);
+}
+
+/// Returns the [`HeldLocks`] of the current processor if available.
+pub fn cpu_locks() -> Option<&'static HeldLocks> {
+    if CPU_LOCKS.is_initialized() {
+        CPU_LOCKS.get()()
+    } else {
+        None
+    }
+}
+
+/// Class of locks created at the same place, embedded in each lock.
+#[derive(Debug)]
+pub struct LockClass {
+    /// Where the lock is created.
+    key: &'static Location<'static>,
+    /// Index of the class plus one, or `0` if not looked up yet.
+    id: AtomicU16,
+}
+
+impl LockClass {
+    /// Constructs the class of locks created at the caller.
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            key: Location::caller(),
+            id: AtomicU16::new(0),
+        }
+    }
+
+    /// Returns the index of the class, registering it if new. Call this with `GRAPH_LOCK` held.
+    fn index(&self, graph: &mut OrderGraph) -> Option<u16> {
+        match self.id.load(Relaxed) {
+            0 => {
+                let index = graph.class_index(self.key)?;
+                self.id.store(index + 1, Relaxed);
+                Some(index)
+            }
+            id => Some(id - 1),
+        }
+    }
+}
+
+impl Default for LockClass {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Locks held by a processor or a task, in the order they were taken.
+#[derive(Debug)]
+pub struct HeldLocks {
+    held: UnsafeCell<[Held; MAX_HELD]>,
+    len: Cell<usize>,
+}
+
+// Safety: A `HeldLocks` is modified only by its owner with interrupts disabled.
+unsafe impl Sync for HeldLocks {}
+
+impl HeldLocks {
+    /// Constructs a record of no locks.
+    pub const fn new() -> Self {
+        Self {
+            held: UnsafeCell::new([Held::EMPTY; MAX_HELD]),
+            len: Cell::new(0),
+        }
+    }
+
+    fn held(&self) -> &[Held] {
+        // Safety: Only the owner accesses it with interrupts disabled.
+        unsafe { &(&*self.held.get())[..self.len.get()] }
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    class: u16,
+    /// Address of the lock.
+    addr: usize,
+    site: &'static Location<'static>,
+}
+
+impl Held {
+    const EMPTY: Self = Self {
+        class: 0,
+        addr: 0,
+        site: Location::caller(),
+    };
+}
+
+/// Records that `owner` takes the lock at `addr` of `class` at `site`, and reports if it may
+/// deadlock. Locks in `also_held`, like ones of the processor running the task `owner`, are
+/// ordered before it as well. Set `trylock` if the lock is taken without waiting, which never
+/// deadlocks.
+///
+/// Call this before waiting for the lock, so that a report is written even if it deadlocks.
+pub fn acquire(
+    owner: &HeldLocks,
+    also_held: Option<&HeldLocks>,
+    class: &LockClass,
+    addr: usize,
+    site: &'static Location<'static>,
+    trylock: bool,
+) {
+    if !ENABLED.load(Relaxed) {
+        return;
+    }
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+
+    let mut report = None;
+    {
+        let mut graph = GraphGuard::lock();
+        let Some(index) = class.index(&mut graph) else {
+            drop(graph);
+            disable("lock classes");
+            restore_if(if_is_set);
+            return;
+        };
+        if !trylock {
+            let also_held = also_held.map_or(&[][..], HeldLocks::held);
+            for &held in also_held.iter().chain(owner.held()) {
+                if held.class == index || has_order(held.class, index) {
+                    continue;
+                }
+                if report.is_none() && graph.find_path(index, held.class).is_some() {
+                    report = Some(held);
+                }
+                if !graph.add_order(held.class, held.site, index, site) {
+                    drop(graph);
+                    disable("lock orders");
+                    restore_if(if_is_set);
+                    return;
+                }
+                ORDERS[held.class as usize][index as usize / 64]
+                    .fetch_or(1 << (index % 64), Relaxed);
+            }
+        }
+
+        let len = owner.len.get();
+        if len == MAX_HELD {
+            drop(graph);
+            disable("held locks");
+            restore_if(if_is_set);
+            return;
+        }
+        // Safety: Only the owner accesses it with interrupts disabled.
+        unsafe {
+            (*owner.held.get())[len] = Held {
+                class: index,
+                addr,
+                site,
+            }
+        };
+        owner.len.set(len + 1);
+    }
+
+    if let Some(held) = report {
+        self::report(held, class, site);
+    }
+    restore_if(if_is_set);
+}
+
+/// Records that `owner` releases the lock at `addr`. Locks can be released in any order.
+pub fn release(owner: &HeldLocks, addr: usize) {
+    let if_is_set = asmfunc::get_if();
+    asmfunc::cli();
+    // Safety: Only the owner accesses it with interrupts disabled.
+    let held = unsafe { &mut *owner.held.get() };
+    let len = owner.len.get();
+    // Not recorded if taken before validation is enabled or after it is disabled.
+    if let Some(i) = held[..len].iter().rposition(|held| held.addr == addr) {
+        held.copy_within(i + 1..len, i);
+        owner.len.set(len - 1);
+    }
+    restore_if(if_is_set);
+}
+
+fn restore_if(if_is_set: bool) {
+    if if_is_set {
+        asmfunc::sti();
+    }
+}
+
+fn has_order(from: u16, to: u16) -> bool {
+    ORDERS[from as usize][to as usize / 64].load(Relaxed) & 1 << (to % 64) != 0
+}
+
+fn disable(table: &str) {
+    if ENABLED.swap(false, Relaxed) {
+        let _ = writeln!(
+            SerialWriter,
+            "[WARN] lockdep: too many {}, lock validation is disabled",
+            table
+        );
+    }
+}
+
+/// Writes the cycle closed by taking a lock of `class` at `site` while holding `held`.
+fn report(held: Held, class: &LockClass, site: &'static Location<'static>) {
+    let mut w = SerialWriter;
+    let _ = writeln!(w, "[ERROR] lockdep: possible deadlock detected");
+    let _ = writeln!(
+        w,
+        "  {} takes a lock created at {} while holding a lock locked at {}",
+        site, class.key, held.site
+    );
+    let _ = writeln!(w, "  but the reverse order has been seen before:");
+    let mut graph = GraphGuard::lock();
+    let index = class.index(&mut graph).unwrap();
+    let Some(path) = graph.find_path(index, held.class) else {
+        return;
+    };
+    // Copy the sites out so as not to write slowly with the lock held.
+    let mut sites = [(held.site, site); MAX_REPORTED];
+    let mut len = 0;
+    let mut omitted = 0;
+    for order in path {
+        if len < MAX_REPORTED {
+            sites[len] = (order.held_site, order.site);
+            len += 1;
+        } else {
+            omitted += 1;
+        }
+    }
+    drop(graph);
+    for (held_site, site) in &sites[..len] {
+        let _ = writeln!(
+            w,
+            "    {} while holding a lock locked at {}",
+            site, held_site
+        );
+    }
+    if omitted != 0 {
+        let _ = writeln!(w, "    and {} more", omitted);
+    }
+}
+
+/// Order of two classes, with the places where it was first seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Order {
+    /// Index of the class held.
+    pub from: u16,
+    /// Index of the class taken while holding `from`.
+    pub to: u16,
+    /// Where the lock of `from` was taken.
+    pub held_site: &'static Location<'static>,
+    /// Where the lock of `to` was taken.
+    pub site: &'static Location<'static>,
+}
+
+impl Order {
+    const EMPTY: Self = Self {
+        from: 0,
+        to: 0,
+        held_site: Location::caller(),
+        site: Location::caller(),
+    };
+}
+
+/// Graph of lock classes whose edges are the orders of taking them. [`acquire()`] validates
+/// against the one shared by all processors, but graphs can be built separately as well.
+#[derive(Debug)]
+pub struct OrderGraph {
+    /// Keys of registered classes.
+    classes: [Option<&'static Location<'static>>; MAX_CLASSES],
+    class_count: usize,
+    orders: [Order; MAX_ORDERS],
+    order_count: usize,
+}
+
+impl OrderGraph {
+    /// Constructs a graph without classes.
+    pub const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            class_count: 0,
+            orders: [Order::EMPTY; MAX_ORDERS],
+            order_count: 0,
+        }
+    }
+
+    /// Returns the index of the class whose key is `key`, registering it if new. Returns `None`
+    /// if the table is full.
+    pub fn class_index(&mut self, key: &'static Location<'static>) -> Option<u16> {
+        let registered = &self.classes[..self.class_count];
+        // Compare by value because the same place may have more than one `Location`.
+        if let Some(i) = registered.iter().position(|&class| class == Some(key)) {
+            return Some(i as u16);
+        }
+        if self.class_count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.class_count] = Some(key);
+        self.class_count += 1;
+        Some(self.class_count as u16 - 1)
+    }
+
+    /// Records the order of taking `to` at `site` while holding `from` taken at `held_site`.
+    /// Returns `false` if the table is full.
+    pub fn add_order(
+        &mut self,
+        from: u16,
+        held_site: &'static Location<'static>,
+        to: u16,
+        site: &'static Location<'static>,
+    ) -> bool {
+        if self.order_count == MAX_ORDERS {
+            return false;
+        }
+        self.orders[self.order_count] = Order {
+            from,
+            to,
+            held_site,
+            site,
+        };
+        self.order_count += 1;
+        true
+    }
+
+    /// Returns the orders on a shortest path from `from` to `to`, if any. Taking `from` while
+    /// holding `to` closes a cycle if this returns a path.
+    pub fn find_path(&self, from: u16, to: u16) -> Option<impl Iterator<Item = Order> + '_> {
+        // Breadth-first search, recording the index of the order used to reach each class.
+        let mut reached_by = [UNREACHED; MAX_CLASSES];
+        let mut queue = [0u16; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        while head < tail && reached_by[to as usize] == UNREACHED {
+            let class = queue[head];
+            head += 1;
+            for (i, order) in self.orders[..self.order_count].iter().enumerate() {
+                if order.from != class
+                    || order.to == from
+                    || reached_by[order.to as usize] != UNREACHED
+                {
+                    continue;
+                }
+                reached_by[order.to as usize] = i as u16;
+                queue[tail] = order.to;
+                tail += 1;
+            }
+        }
+        if reached_by[to as usize] == UNREACHED {
+            return None;
+        }
+
+        // Walk back from `to`, then return the path in order.
+        let mut path = [0u16; MAX_CLASSES];
+        let mut len = 0;
+        let mut class = to;
+        while class != from {
+            let i = reached_by[class as usize];
+            path[len] = i;
+            len += 1;
+            class = self.orders[i as usize].from;
+        }
+        Some((0..len).rev().map(move |j| self.orders[path[j] as usize]))
+    }
+}
+
+impl Default for OrderGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct GraphCell(UnsafeCell<OrderGraph>);
+
+// Safety: `OrderGraph` is accessed only with `GRAPH_LOCK` held.
+unsafe impl Sync for GraphCell {}
+
+/// Holder of [`GRAPH_LOCK`]. Take it with interrupts disabled.
+struct GraphGuard(&'static mut OrderGraph);
+
+impl GraphGuard {
+    fn lock() -> Self {
+        while GRAPH_LOCK
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        // Safety: `GRAPH_LOCK` is held.
+        Self(unsafe { &mut *GRAPH.0.get() })
+    }
+}
+
+impl core::ops::Deref for GraphGuard {
+    type Target = OrderGraph;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl core::ops::DerefMut for GraphGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+impl Drop for GraphGuard {
+    fn drop(&mut self) {
+        GRAPH_LOCK.store(false, Release);
+    }
+}
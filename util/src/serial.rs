@@ -0,0 +1,36 @@
+//! Provides output to the serial port.
+//!
+//! Writing takes no lock and does not allocate, so it is usable where the logger is not, e.g. in
+//! fault handlers and in reports of locks.
+
+use core::fmt::{self, Write};
+
+use crate::{asmfunc, bitfield::BitField as _};
+
+/// I/O port base of COM1.
+const UART_BASE_PORT: u16 = 0x3f8;
+
+/// I/O port of the line status register.
+const LINE_PORT: u16 = UART_BASE_PORT + 5;
+
+/// Writes `bytes` to the serial port. Waits until the transmitter gets ready for each byte.
+pub fn write_bytes(bytes: &[u8]) {
+    for &b in bytes {
+        while !asmfunc::io_inb(LINE_PORT).get_bit(5) {
+            core::hint::spin_loop();
+        }
+        asmfunc::io_outb(UART_BASE_PORT, b);
+    }
+}
+
+/// Provides [`Write`] to the serial port. It takes no lock and does not allocate, so it is usable
+/// even when the kernel state is broken, e.g. in fault handlers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
@@ -3,13 +3,19 @@
 use core::{
     any,
     cell::UnsafeCell,
+    fmt::Debug,
     hint,
-    mem::MaybeUninit,
+    mem::{self, MaybeUninit},
     ops::{Deref, DerefMut},
-    sync::atomic::{self, AtomicBool, Ordering::*},
+    sync::atomic::{self, AtomicBool, AtomicU32, Ordering::*},
 };
 
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
 use crate::asmfunc;
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
 
 /// Immutable static variable that will be initialized after running program. You cannot change
 /// inner value after you once initialized.
@@ -87,6 +93,57 @@ impl<T> OnceStatic<T> {
         self.is_initialized.load(Relaxed)
     }
 
+    /// Returns the inner value as a reference to it, or `None` if it is not initialized.
+    pub fn try_get(&self) -> Option<&T> {
+        if self.is_initialized.load(Acquire) {
+            // Safety: `data` is never written after `is_initialized` is set, so reading it does
+            //         not race.
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the inner value, initializing it to the value returned by `f` if not initialized.
+    ///
+    /// Even if called on more than one processor at once, `f` runs only once and the others wait
+    /// for it. So `f` must not call this on `self`, which waits forever. If `f` panics, `self` is
+    /// left uninitialized, and the next call runs its own `f`.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        /// Releases the lock when dropped, even by unwinding from `f`.
+        struct Unlock<'a>(&'a AtomicBool);
+
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Release);
+            }
+        }
+
+        if let Some(value) = self.try_get() {
+            return value;
+        }
+
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        let unlock = Unlock(&self.lock);
+        // Initialized while waiting for the lock.
+        if !self.is_initialized.load(Relaxed) {
+            // Safety: Only the holder of `lock` writes `data`, so writing it does not race.
+            unsafe { (*self.data.get()).write(f()) };
+            // Pairs with `Acquire` in `try_get()`. See `init()`.
+            atomic::fence(Release);
+            self.is_initialized.store(true, Relaxed);
+        }
+        drop(unlock);
+        // Safety: It is initialized above or by others holding the lock.
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
     /// Returns the inner value as a reference to it.
     ///
     /// For a safe alternative see [`as_ref`][AsRef::as_ref].
@@ -177,15 +234,158 @@ impl<T> Deref for OnceStatic<T> {
     }
 }
 
+/// Static variable initialized by `F` on the first access, which does not need an explicit call
+/// to initialize in the right order.
+///
+/// ```ignore
+/// static TABLE: Lazy<Table> = Lazy::new(|| Table::parse(rsdp()));
+/// let entry = TABLE.find(signature);
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceStatic<T>,
+    /// Initializer, taken when it runs.
+    init: UnsafeCell<Option<F>>,
+}
+
+// Safety: The value may be initialized by any thread and then shared, so `T` must be `Send` and
+//      `Sync`. `init` is taken only once with the lock of `cell`, so `F` must be `Send`.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Constructs one initialized by `f` on the first access.
+    pub const fn new(f: F) -> Self {
+        Self {
+            cell: OnceStatic::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+
+    /// Returns the inner value, initializing it if this is the first access.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the initializer panics, and on every later access because it has been consumed.
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| {
+            // Safety: This runs only once with the lock of `cell` held.
+            let init = unsafe { (*this.init.get()).take() };
+            match init {
+                Some(init) => init(),
+                None => panic!("Lazy instance has previously been poisoned"),
+            }
+        })
+    }
+
+    /// Returns the inner value, or `None` if it has not been accessed yet.
+    pub fn try_get(this: &Self) -> Option<&T> {
+        this.cell.try_get()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        Self::force(self)
+    }
+}
+
+impl<T: Debug, F> Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut f = f.debug_tuple("Lazy");
+        match self.cell.try_get() {
+            Some(value) => f.field(value),
+            None => f.field(&format_args!("<uninit>")),
+        };
+        f.finish()
+    }
+}
+
+/// Counters of a ticket lock, which is taken in the order of taking tickets. Guards neither data
+/// nor interrupts by itself. [`InterruptFreeMutex`] is built on it.
+#[derive(Debug)]
+pub struct TicketLock {
+    /// Ticket handed to the next one trying to lock.
+    next_ticket: AtomicU32,
+    /// Ticket of the one holding or allowed to take the lock.
+    now_serving: AtomicU32,
+}
+
+impl TicketLock {
+    /// Constructs a new unlocked [`TicketLock`].
+    pub const fn new() -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+        }
+    }
+
+    /// Takes the lock and returns `true` if no one holds or waits for it.
+    pub fn try_lock(&self) -> bool {
+        // The lock is free only when no ticket is handed out beyond the one served.
+        let serving = self.now_serving.load(Relaxed);
+        self.next_ticket
+            .compare_exchange(serving, serving.wrapping_add(1), Acquire, Relaxed)
+            .is_ok()
+    }
+
+    /// Spins until taking the lock.
+    pub fn lock(&self) {
+        self.lock_with(|| {});
+    }
+
+    /// Spins until taking the lock, calling `spin` on every turn of waiting.
+    pub fn lock_with(&self, mut spin: impl FnMut()) {
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        while self.now_serving.load(Acquire) != ticket {
+            hint::spin_loop();
+            spin();
+        }
+    }
+
+    /// Hands the lock to the next one waiting for it.
+    ///
+    /// # Safety
+    ///
+    /// The caller must hold the lock. Otherwise, this lets another one take it while it is held.
+    pub unsafe fn unlock(&self) {
+        // Only the holder advances it.
+        self.now_serving.fetch_add(1, Release);
+    }
+
+    /// Returns whether someone holds the lock.
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Relaxed) != self.now_serving.load(Relaxed)
+    }
+}
+
+impl Default for TicketLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Provides a mutex lock with disabling interrupts until release the lock.
+///
+/// This is a ticket lock, so processors acquire it in the order they start to wait. Whether
+/// interrupts were enabled is saved in each guard and restored when it is dropped, so drop guards
+/// of nested locks in the reverse order of locking.
+///
+/// With the `lock-debug` feature, the lock records the processor and the caller holding it, panics
+/// when the processor holding it tries to lock it again, and warns on COM1 when spinning long. Call
+/// [`set_cpu_id()`] to identify processors cheaply. With the `lockdep` feature, the order of taking
+/// it is validated by [`lockdep`](crate::lockdep).
 #[derive(Debug)]
 pub struct InterruptFreeMutex<T> {
     /// Data guarded by the lock.
     data: UnsafeCell<T>,
-    /// Represents whether someone has the lock.
-    locker: AtomicBool,
-    /// Saves whether IF (interrupt flag) is set before disabling interrupts.
-    prev_if: AtomicBool,
+    /// Counters processors take tickets from.
+    lock: TicketLock,
+    /// Owner of the lock, recorded for debugging.
+    #[cfg(feature = "lock-debug")]
+    owner: debug::Owner,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::LockClass,
 }
 
 // Safety: `InterruptFreeMutex<T>` and its shared referenece only provide an exclusive mutability to
@@ -195,46 +395,85 @@ unsafe impl<T: Send> Sync for InterruptFreeMutex<T> {}
 
 impl<T> InterruptFreeMutex<T> {
     /// Constructs new [`InterruptFreeMutex`] whose initial value is `value`.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub const fn new(value: T) -> Self {
         Self {
             data: UnsafeCell::new(value),
-            locker: AtomicBool::new(false),
-            prev_if: AtomicBool::new(false),
+            lock: TicketLock::new(),
+            #[cfg(feature = "lock-debug")]
+            owner: debug::Owner::new(),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::LockClass::new(),
         }
     }
 
     /// Trys to take a lock, and if succeeds returns the guard. Otherwise, returns `None`.
+    #[cfg_attr(any(feature = "lock-debug", feature = "lockdep"), track_caller)]
     pub fn try_lock(&self) -> Option<InterruptFreeMutexGuard<'_, T>> {
-        self.prev_if.store(asmfunc::get_if(), Relaxed);
+        let prev_if = asmfunc::get_if();
         // NOTE: We disable interrupts even if interrupts are already disabled because conditional
         //       branching is expensive.
         asmfunc::cli();
 
-        if self.locker.swap(true, Relaxed) {
-            if self.prev_if.load(Relaxed) {
+        if !self.lock.try_lock() {
+            if prev_if {
                 asmfunc::sti();
             }
-            None
-        } else {
-            // Since we don't need `Acquire` when failed, put fence here.
-            atomic::fence(Acquire);
-            Some(InterruptFreeMutexGuard {
-                data: unsafe { &mut *self.data.get() },
-                locker: &self.locker,
-                prev_if: &self.prev_if,
-            })
+            return None;
         }
+        #[cfg(feature = "lockdep")]
+        self.validate(true);
+        Some(self.guard(prev_if))
     }
 
     /// Until succeeding acuiring the lock, spins loop. Then returns the guard.
     ///
     /// If you do not need to lock definitely, use [`InterruptFreeMutex::try_lock()`] instead.
+    #[cfg_attr(any(feature = "lock-debug", feature = "lockdep"), track_caller)]
     pub fn lock(&self) -> InterruptFreeMutexGuard<'_, T> {
-        loop {
-            match self.try_lock() {
-                Some(guard) => break guard,
-                None => hint::spin_loop(),
-            }
+        let prev_if = asmfunc::get_if();
+        asmfunc::cli();
+
+        #[cfg(feature = "lock-debug")]
+        self.owner.check_deadlock(self.is_locked());
+        #[cfg(feature = "lockdep")]
+        self.validate(false);
+        #[cfg(feature = "lock-debug")]
+        let mut spin = debug::Spin::new();
+        // Interrupts stay disabled while spinning because an interrupt handler taking the same
+        // lock would wait behind our ticket forever.
+        self.lock.lock_with(|| {
+            #[cfg(feature = "lock-debug")]
+            spin.tick(&self.owner);
+        });
+        self.guard(prev_if)
+    }
+
+    /// Returns whether someone holds the lock.
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    /// Records taking the lock in the held locks of the current processor.
+    #[cfg(feature = "lockdep")]
+    #[track_caller]
+    fn validate(&self, trylock: bool) {
+        if let Some(held) = lockdep::cpu_locks() {
+            let addr = self as *const Self as usize;
+            lockdep::acquire(held, None, &self.class, addr, Location::caller(), trylock);
+        }
+    }
+
+    /// Returns the guard of the lock just acquired.
+    #[cfg_attr(any(feature = "lock-debug", feature = "lockdep"), track_caller)]
+    fn guard(&self, prev_if: bool) -> InterruptFreeMutexGuard<'_, T> {
+        #[cfg(feature = "lock-debug")]
+        self.owner.acquire();
+        InterruptFreeMutexGuard {
+            // Safety: The lock is held until the guard is dropped.
+            data: unsafe { &mut *self.data.get() },
+            mutex: self,
+            prev_if,
         }
     }
 }
@@ -244,10 +483,21 @@ impl<T> InterruptFreeMutex<T> {
 pub struct InterruptFreeMutexGuard<'this, T> {
     /// Guarded data.
     data: &'this mut T,
-    /// Reference to value representing whether it is locked. Used to release the lock.
-    locker: &'this AtomicBool,
-    /// Reference to value representing whether IF is set.
-    prev_if: &'this AtomicBool,
+    /// Lock to release.
+    mutex: &'this InterruptFreeMutex<T>,
+    /// Whether IF was set before locking.
+    prev_if: bool,
+}
+
+impl<T> InterruptFreeMutexGuard<'_, T> {
+    /// Releases the lock leaving interrupts disabled, and returns whether IF was set before
+    /// locking. Lets a lock be released right before switching contexts, where interrupts must
+    /// stay disabled.
+    pub fn unlock_keeping_cli(mut this: Self) -> bool {
+        let prev_if = mem::take(&mut this.prev_if);
+        drop(this);
+        prev_if
+    }
 }
 
 impl<T> Deref for InterruptFreeMutexGuard<'_, T> {
@@ -266,9 +516,147 @@ impl<T> DerefMut for InterruptFreeMutexGuard<'_, T> {
 
 impl<T> Drop for InterruptFreeMutexGuard<'_, T> {
     fn drop(&mut self) {
-        self.locker.store(false, Release);
-        if self.prev_if.load(Relaxed) {
+        #[cfg(feature = "lock-debug")]
+        self.mutex.owner.release();
+        #[cfg(feature = "lockdep")]
+        if let Some(held) = lockdep::cpu_locks() {
+            lockdep::release(held, self.mutex as *const _ as usize);
+        }
+        // Safety: The guard exists only while the lock is held.
+        unsafe { self.mutex.lock.unlock() };
+        if self.prev_if {
             asmfunc::sti();
         }
     }
 }
+
+#[cfg(feature = "lock-debug")]
+pub use debug::set_cpu_id;
+
+#[cfg(feature = "lock-debug")]
+mod debug {
+    //! Records owners of [`InterruptFreeMutex`](super::InterruptFreeMutex) to find misuse.
+
+    use core::{
+        fmt::Write,
+        panic::Location,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU32, Ordering::*},
+    };
+
+    use super::OnceStatic;
+    use crate::{asmfunc, serial::SerialWriter};
+
+    /// `Owner::cpu` when no one holds the lock.
+    const NO_CPU: u32 = u32::MAX;
+
+    /// Cycles spun before warning.
+    const LONG_SPIN_CYCLES: u64 = 1 << 32;
+
+    /// Returns the APIC ID of the current processor, registered by [`set_cpu_id()`].
+    static CPU_ID: OnceStatic<fn() -> Option<u32>> = OnceStatic::new();
+
+    /// Registers `f` returning the APIC ID of the current processor, or `None` if it is not
+    /// available yet. Until then, CPUID is executed on every lock, which exits to the hypervisor
+    /// on virtual machines.
+    pub fn set_cpu_id(f: fn() -> Option<u32>) {
+        CPU_ID.init(f);
+    }
+
+    /// Processor and caller holding a lock.
+    #[derive(Debug)]
+    pub struct Owner {
+        /// Initial APIC ID of the processor, or [`NO_CPU`].
+        cpu: AtomicU32,
+        /// Where the lock is taken, or null.
+        location: AtomicPtr<Location<'static>>,
+    }
+
+    impl Owner {
+        pub const fn new() -> Self {
+            Self {
+                cpu: AtomicU32::new(NO_CPU),
+                location: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+
+        /// Panics if the current processor holds the lock, which it would wait for forever.
+        #[track_caller]
+        pub fn check_deadlock(&self, locked: bool) {
+            // Others never record the current processor, and it clears the record before
+            // releasing.
+            if locked && self.cpu.load(Relaxed) == cpu_id() {
+                panic!(
+                    "deadlock: {} locks a lock already held by {} on the same processor",
+                    Location::caller(),
+                    self.describe_location()
+                );
+            }
+        }
+
+        #[track_caller]
+        pub fn acquire(&self) {
+            let location = Location::caller() as *const _ as *mut _;
+            self.location.store(location, Relaxed);
+            self.cpu.store(cpu_id(), Relaxed);
+        }
+
+        pub fn release(&self) {
+            self.cpu.store(NO_CPU, Relaxed);
+            self.location.store(ptr::null_mut(), Relaxed);
+        }
+
+        fn describe_location(&self) -> &'static dyn core::fmt::Display {
+            // Safety: Only `&'static Location` are stored.
+            match unsafe { self.location.load(Relaxed).as_ref() } {
+                Some(location) => location,
+                None => &"<unknown>",
+            }
+        }
+    }
+
+    /// Measures how long a processor spins for a lock.
+    pub struct Spin {
+        start: u64,
+        warned: bool,
+    }
+
+    impl Spin {
+        pub fn new() -> Self {
+            Self {
+                start: asmfunc::rdtsc(),
+                warned: false,
+            }
+        }
+
+        /// Warns once if spinning too long. The logger takes locks, which may be the one spun
+        /// for, so this writes to [`SerialWriter`].
+        #[track_caller]
+        pub fn tick(&mut self, owner: &Owner) {
+            if self.warned || asmfunc::rdtsc().wrapping_sub(self.start) < LONG_SPIN_CYCLES {
+                return;
+            }
+            self.warned = true;
+            let _ = writeln!(
+                SerialWriter,
+                "[WARN] {} on processor {} has been spinning long for a lock held by {} on \
+                 processor {}",
+                Location::caller(),
+                cpu_id(),
+                owner.describe_location(),
+                owner.cpu.load(Relaxed)
+            );
+        }
+    }
+
+    /// Returns the APIC ID of the current processor.
+    fn cpu_id() -> u32 {
+        if CPU_ID.is_initialized()
+            && let Some(id) = CPU_ID.get()()
+        {
+            return id;
+        }
+        // Initial APIC ID. Local APIC may not be mapped yet.
+        asmfunc::cpuid(1).1 >> 24
+    }
+}
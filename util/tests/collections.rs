@@ -27,3 +27,20 @@ fn hash_map_test() {
         assert_eq!(map.remove(&format!("{}", i)).unwrap(), 3 * i);
     }
 }
+
+#[test]
+fn hash_map_iter_test() {
+    let mut map = HashMap::new();
+    assert_eq!(map.iter().count(), 0);
+
+    for i in 0..100u32 {
+        map.insert(i, 2 * i);
+    }
+    for i in 0..50u32 {
+        map.remove(&i);
+    }
+    let mut pairs: Vec<_> = map.iter().map(|(&k, &v)| (k, v)).collect();
+    pairs.sort_unstable();
+    assert_eq!(pairs, (50..100).map(|i| (i, 2 * i)).collect::<Vec<_>>());
+    assert_eq!(map.values().sum::<u32>(), (50..100).map(|i| 2 * i).sum());
+}
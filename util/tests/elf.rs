@@ -0,0 +1,65 @@
+use util::elf::{Elf64Sym, ElfSymBinding, ElfSymType, SymbolTable, TlsTemplate, get_str};
+
+fn sym(name: u32, ty: ElfSymType, value: u64, size: u64) -> Elf64Sym {
+    Elf64Sym {
+        name,
+        info: (ElfSymBinding::GLOBAL.0 << 4) | ty.0,
+        other: 0,
+        shndx: 1,
+        value,
+        size,
+    }
+}
+
+#[test]
+fn get_str_test() {
+    let strtab = b"\0foo\0bar\0baz";
+    assert_eq!(get_str(strtab, 0), Some(""));
+    assert_eq!(get_str(strtab, 1), Some("foo"));
+    assert_eq!(get_str(strtab, 6), Some("ar"));
+    // Not terminated.
+    assert_eq!(get_str(strtab, 9), None);
+    assert_eq!(get_str(strtab, 100), None);
+}
+
+#[test]
+fn symbol_lookup_test() {
+    let strtab = b"\0foo\0bar\0label\0data\0";
+    let symbols = [
+        sym(0, ElfSymType::NOTYPE, 0, 0),
+        sym(1, ElfSymType::FUNC, 0x1000, 0x100),
+        sym(5, ElfSymType::FUNC, 0x1100, 0x80),
+        sym(9, ElfSymType::NOTYPE, 0x2000, 0),
+        sym(15, ElfSymType::OBJECT, 0x3000, 0x10),
+    ];
+    let table = SymbolTable::new(&symbols, strtab);
+
+    assert_eq!(table.symbols()[1].ty(), ElfSymType::FUNC);
+    assert_eq!(table.symbols()[1].binding(), ElfSymBinding::GLOBAL);
+    assert_eq!(table.name(&symbols[2]), Some("bar"));
+
+    assert_eq!(table.lookup(0xfff), None);
+    assert_eq!(table.lookup(0x1000), Some(("foo", 0)));
+    assert_eq!(table.lookup(0x10ff), Some(("foo", 0xff)));
+    assert_eq!(table.lookup(0x1100), Some(("bar", 0)));
+    // Between functions and labels, nothing contains the address.
+    assert_eq!(table.lookup(0x1180), None);
+    assert_eq!(table.lookup(0x2010), Some(("label", 0x10)));
+    // Labels end at the next symbol, and objects are not code.
+    assert_eq!(table.lookup(0x3008), None);
+}
+
+#[test]
+fn tls_tp_offset_test() {
+    let template = |mem_size, align| TlsTemplate {
+        addr: 0x1000,
+        file_size: 0,
+        mem_size,
+        align,
+    };
+    assert_eq!(template(0, 0).tp_offset(), 0);
+    assert_eq!(template(0x14, 0).tp_offset(), 0x14);
+    assert_eq!(template(0x14, 8).tp_offset(), 0x18);
+    assert_eq!(template(0x20, 16).tp_offset(), 0x20);
+    assert_eq!(template(0x21, 64).tp_offset(), 0x40);
+}
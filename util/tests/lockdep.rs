@@ -0,0 +1,84 @@
+#![cfg(feature = "lockdep")]
+
+use std::panic::Location;
+
+use util::lockdep::{Order, OrderGraph};
+
+#[track_caller]
+fn site() -> &'static Location<'static> {
+    Location::caller()
+}
+
+#[test]
+fn order_graph_class_index_test() {
+    let mut graph = OrderGraph::new();
+    let (a, b) = (site(), site());
+    assert_eq!(graph.class_index(a), Some(0));
+    assert_eq!(graph.class_index(b), Some(1));
+    assert_eq!(graph.class_index(a), Some(0));
+}
+
+#[test]
+fn order_graph_cycle_test() {
+    let mut graph = OrderGraph::new();
+    let sites = [site(), site(), site(), site()];
+    // A -> B -> C, and D unrelated.
+    assert!(graph.add_order(0, sites[0], 1, sites[1]));
+    assert!(graph.add_order(1, sites[1], 2, sites[2]));
+
+    let path: Vec<Order> = graph.find_path(0, 2).unwrap().collect();
+    assert_eq!(
+        path,
+        [
+            Order {
+                from: 0,
+                to: 1,
+                held_site: sites[0],
+                site: sites[1],
+            },
+            Order {
+                from: 1,
+                to: 2,
+                held_site: sites[1],
+                site: sites[2],
+            },
+        ]
+    );
+    // Taking A while holding C closes a cycle, but taking C while holding A does not.
+    assert!(graph.find_path(0, 2).is_some());
+    assert!(graph.find_path(2, 0).is_none());
+    assert!(graph.find_path(0, 3).is_none());
+
+    assert!(graph.add_order(2, sites[2], 0, sites[0]));
+    assert_eq!(graph.find_path(2, 1).unwrap().count(), 2);
+    assert_eq!(graph.find_path(1, 0).unwrap().count(), 2);
+}
+
+#[test]
+fn order_graph_shortest_path_test() {
+    let mut graph = OrderGraph::new();
+    let s = site();
+    assert!(graph.add_order(0, s, 1, s));
+    assert!(graph.add_order(1, s, 2, s));
+    assert!(graph.add_order(2, s, 3, s));
+    assert!(graph.add_order(0, s, 3, s));
+
+    let path: Vec<_> = graph
+        .find_path(0, 3)
+        .unwrap()
+        .map(|o| (o.from, o.to))
+        .collect();
+    assert_eq!(path, [(0, 3)]);
+}
+
+#[test]
+fn order_graph_full_test() {
+    let mut graph = OrderGraph::new();
+    let s = site();
+    let added = (0..)
+        .take_while(|&i| graph.add_order(i % 2, s, 2, s))
+        .count();
+    assert!(added > 0);
+    assert!(!graph.add_order(0, s, 1, s));
+    assert!(graph.find_path(0, 1).is_none());
+}
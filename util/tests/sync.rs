@@ -1,4 +1,13 @@
-use util::sync::OnceStatic;
+use std::{
+    panic,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering::*},
+    },
+    thread,
+};
+
+use util::sync::{Lazy, OnceStatic, TicketLock};
 
 #[test]
 fn test_once_static_init() {
@@ -39,3 +48,147 @@ fn test_once_static_panic_as_get() {
     static VAL: OnceStatic<char> = OnceStatic::new();
     VAL.get();
 }
+
+#[test]
+fn test_once_static_try_get() {
+    static VAL: OnceStatic<i32> = OnceStatic::new();
+    assert_eq!(VAL.try_get(), None);
+
+    VAL.init(7);
+    assert_eq!(VAL.try_get(), Some(&7));
+}
+
+#[test]
+fn test_once_static_get_or_init() {
+    static VAL: OnceStatic<usize> = OnceStatic::new();
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                *VAL.get_or_init(|| {
+                    CALLS.fetch_add(1, Relaxed);
+                    i
+                })
+            })
+        })
+        .collect();
+    let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+    assert_eq!(CALLS.load(Relaxed), 1);
+    assert!(values.iter().all(|&value| value == VAL.get()));
+    assert_eq!(*VAL.get_or_init(|| unreachable!()), VAL.get());
+}
+
+#[test]
+fn test_lazy() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static VAL: Lazy<String> = Lazy::new(|| {
+        CALLS.fetch_add(1, Relaxed);
+        "lazy".to_string()
+    });
+    assert_eq!(Lazy::try_get(&VAL), None);
+
+    let threads: Vec<_> = (0..8).map(|_| thread::spawn(|| VAL.len())).collect();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), 4);
+    }
+
+    assert_eq!(CALLS.load(Relaxed), 1);
+    assert_eq!(Lazy::try_get(&VAL).map(String::as_str), Some("lazy"));
+    assert_eq!(*Lazy::force(&VAL), "lazy");
+}
+
+#[test]
+fn test_once_static_get_or_init_panic() {
+    static VAL: OnceStatic<i32> = OnceStatic::new();
+
+    assert!(panic::catch_unwind(|| VAL.get_or_init(|| panic!("init failed"))).is_err());
+    assert!(!VAL.is_initialized());
+    // The lock is released by unwinding, so the next call initializes it.
+    assert_eq!(*VAL.get_or_init(|| 3), 3);
+}
+
+#[test]
+fn test_lazy_poisoned() {
+    static VAL: Lazy<i32> = Lazy::new(|| panic!("init failed"));
+
+    assert!(panic::catch_unwind(|| *VAL).is_err());
+    let err = panic::catch_unwind(|| *VAL).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<&str>(),
+        Some(&"Lazy instance has previously been poisoned")
+    );
+    assert_eq!(Lazy::try_get(&VAL), None);
+}
+
+#[test]
+fn test_ticket_lock_fifo() {
+    static LOCK: TicketLock = TicketLock::new();
+    static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    LOCK.lock();
+    assert!(LOCK.is_locked());
+    // Each thread starts waiting only after the previous one has taken its ticket.
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let waiting = Arc::new(AtomicBool::new(false));
+            let thread = thread::spawn({
+                let waiting = Arc::clone(&waiting);
+                move || {
+                    LOCK.lock_with(|| waiting.store(true, Relaxed));
+                    ORDER.lock().unwrap().push(i);
+                    unsafe { LOCK.unlock() };
+                }
+            });
+            while !waiting.load(Relaxed) {
+                thread::yield_now();
+            }
+            thread
+        })
+        .collect();
+    unsafe { LOCK.unlock() };
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(*ORDER.lock().unwrap(), [0, 1, 2, 3]);
+    assert!(!LOCK.is_locked());
+}
+
+#[test]
+fn test_ticket_lock_try_lock() {
+    let lock = TicketLock::new();
+    assert!(!lock.is_locked());
+    assert!(lock.try_lock());
+    assert!(lock.is_locked());
+    assert!(!lock.try_lock());
+
+    unsafe { lock.unlock() };
+    assert!(!lock.is_locked());
+    assert!(lock.try_lock());
+}
+
+#[test]
+fn test_ticket_lock_threads() {
+    static LOCK: TicketLock = TicketLock::new();
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..1000 {
+                    LOCK.lock();
+                    // Not atomic as a whole, so updates are lost unless the lock excludes others.
+                    let count = COUNT.load(Relaxed);
+                    COUNT.store(count + 1, Relaxed);
+                    unsafe { LOCK.unlock() };
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(COUNT.load(Relaxed), 8000);
+    assert!(!LOCK.is_locked());
+}